@@ -0,0 +1,315 @@
+use core::mem;
+use x86_64::PhysAddr;
+use crate::memory;
+
+/// Maximum number of I/O APICs that are recorded from the MADT.
+pub const MAX_IO_APICS: usize = 4;
+/// Maximum number of interrupt source overrides recorded from the MADT.
+pub const MAX_OVERRIDES: usize = 16;
+
+/// Root System Description Pointer, as of ACPI 2.0.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Only valid for revision 2 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the RSDP of revision 0, which ends after `rsdt_address`.
+const RSDP_V1_SIZE: usize = 20;
+
+/// Header shared by all system description tables.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// MADT entry types
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// MADT flag indicating that the system has 8259 PICs as well.
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+/// An I/O APIC described by the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt the I/O APIC handles
+    pub gsi_base: u32,
+}
+
+/// A legacy ISA interrupt that is not connected to the global system
+/// interrupt of the same number, or that has a different polarity or
+/// trigger mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    /// The ISA IRQ
+    pub source: u8,
+    /// The global system interrupt it is connected to
+    pub gsi: u32,
+    /// The interrupt is active low instead of active high
+    pub active_low: bool,
+    /// The interrupt is level triggered instead of edge triggered
+    pub level_triggered: bool,
+}
+
+/// The parts of the Multiple APIC Description Table the kernel uses.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The system has 8259 PICs, which have to be disabled when the
+    /// APICs are used
+    pub has_legacy_pics: bool,
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+impl Madt {
+    /// Returns the global system interrupt and the override (if any) of
+    /// the given ISA IRQ.
+    pub fn isa_irq(&self, irq: u8) -> (u32, Option<InterruptOverride>) {
+        match self.overrides.iter().flatten().find(|o| o.source == irq) {
+            Some(o) => (o.gsi, Some(*o)),
+            None => (irq as u32, None),
+        }
+    }
+}
+
+/// The High Precision Event Timer block described by the HPET table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetInfo {
+    /// Physical address of the register block
+    pub address: PhysAddr,
+    /// Sequence number of the timer block
+    pub number: u8,
+    /// Smallest comparator increment that doesn't lose interrupts in
+    /// periodic mode, in main counter cycles
+    pub min_tick: u16,
+}
+
+/// Address space ID of memory in a generic address structure.
+const GAS_SYSTEM_MEMORY: u8 = 0;
+
+/// Returns a pointer to the physical address through the physical memory
+/// mapping.
+fn phys_ptr<T>(addr: PhysAddr) -> *const T {
+    (memory::physical_memory_offset() + addr.as_u64()).as_ptr()
+}
+
+/// Reads a `T` from the physical address, which doesn't have to be aligned.
+fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    unsafe { phys_ptr::<T>(addr).read_unaligned() }
+}
+
+/// Returns `true` if the `len` bytes at `addr` add up to 0, which is how
+/// all ACPI structures are checksummed.
+fn checksum_valid(addr: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_ptr::<u8>(addr), len) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Returns the header of the table at `addr` if the table is at least as
+/// long as its header and has a valid checksum.
+fn table_header(addr: PhysAddr) -> Option<SdtHeader> {
+    let header: SdtHeader = read_phys(addr);
+    // A broken table could claim to end before its own header
+    let length = header.length as usize;
+    if length < mem::size_of::<SdtHeader>() || !checksum_valid(addr, length) {
+        return None;
+    }
+    Some(header)
+}
+
+/// Returns the RSDP at `addr` if it has a valid signature and checksum.
+fn rsdp_at(addr: PhysAddr) -> Option<Rsdp> {
+    let rsdp: Rsdp = read_phys(addr);
+    if &rsdp.signature != b"RSD PTR " || !checksum_valid(addr, RSDP_V1_SIZE) {
+        return None;
+    }
+    if rsdp.revision >= 2 && !checksum_valid(addr, rsdp.length as usize) {
+        return None;
+    }
+    Some(rsdp)
+}
+
+/// Searches the RSDP in the first KiB of the extended BIOS data area and
+/// in the BIOS ROM, it is always 16 byte aligned.
+fn find_rsdp() -> Option<(PhysAddr, Rsdp)> {
+    // The real mode segment of the EBDA is stored in the BIOS data area
+    let ebda = (read_phys::<u16>(PhysAddr::new(0x40e)) as u64) << 4;
+    let ebda_range = ebda..ebda + 1024;
+    let rom_range = 0xe_0000..0x10_0000;
+
+    ebda_range
+        .step_by(16)
+        .chain(rom_range.step_by(16))
+        .map(PhysAddr::new)
+        .find_map(|addr| rsdp_at(addr).map(|rsdp| (addr, rsdp)))
+}
+
+/// Returns the physical address of the RSDP, or `None` if the system has
+/// no ACPI tables.
+pub fn rsdp_address() -> Option<PhysAddr> {
+    find_rsdp().map(|(addr, _)| addr)
+}
+
+/// Returns the physical address of the first table with the given
+/// signature that has a valid checksum.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let (_, rsdp) = find_rsdp()?;
+
+    // The XSDT has 64 bit entries, the RSDT of ACPI 1.0 32 bit entries
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+    };
+    let header = table_header(root)?;
+
+    let entries = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + mem::size_of::<SdtHeader>() + i * entry_size;
+            if entry_size == 8 {
+                PhysAddr::new(read_phys::<u64>(entry))
+            } else {
+                PhysAddr::new(read_phys::<u32>(entry) as u64)
+            }
+        })
+        .find(|&table| table_header(table).is_some_and(|header| &header.signature == signature))
+}
+
+/// Parses the Multiple APIC Description Table, returns `None` if the system
+/// has none.
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let header: SdtHeader = read_phys(table);
+    let end = table + header.length as u64;
+    // The local APIC address and the flags must fit into the table
+    if (header.length as usize) < mem::size_of::<SdtHeader>() + 8 {
+        return None;
+    }
+
+    // The header is followed by the local APIC address and the flags
+    let fields = table + mem::size_of::<SdtHeader>();
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_phys::<u32>(fields) as u64),
+        has_legacy_pics: read_phys::<u32>(fields + 4u64) & MADT_PCAT_COMPAT != 0,
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+
+    // Variable length entries, each starts with its type and length
+    let mut entry = fields + 8u64;
+    while entry + 2u64 <= end {
+        let entry_type: u8 = read_phys(entry);
+        let length: u8 = read_phys(entry + 1u64);
+        if length < 2 {
+            // A broken table, stop before looping forever
+            break;
+        }
+        match entry_type {
+            MADT_IO_APIC => {
+                let io_apic = IoApicInfo {
+                    id: read_phys(entry + 2u64),
+                    address: PhysAddr::new(read_phys::<u32>(entry + 4u64) as u64),
+                    gsi_base: read_phys(entry + 8u64),
+                };
+                if let Some(slot) = madt.io_apics.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some(io_apic);
+                }
+            }
+            MADT_INTERRUPT_OVERRIDE => {
+                let flags: u16 = read_phys(entry + 8u64);
+                let interrupt_override = InterruptOverride {
+                    source: read_phys(entry + 3u64),
+                    gsi: read_phys(entry + 4u64),
+                    // Bits 0-1 polarity and 2-3 trigger mode, 0 means
+                    // the default of the ISA bus (active high, edge)
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                };
+                if let Some(slot) = madt.overrides.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some(interrupt_override);
+                }
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_address = PhysAddr::new(read_phys(entry + 4u64));
+            }
+            _ => {}
+        }
+        entry += length as u64;
+    }
+
+    Some(madt)
+}
+
+/// Parses the HPET table, returns `None` if the system has no HPET or its
+/// registers are not memory mapped.
+pub fn hpet() -> Option<HpetInfo> {
+    let table = find_table(b"HPET")?;
+    let header: SdtHeader = read_phys(table);
+    // The fields up to the minimum tick must fit into the table
+    if (header.length as usize) < mem::size_of::<SdtHeader>() + 19 {
+        return None;
+    }
+
+    // The header is followed by the event timer block ID and the base
+    // address as a generic address structure
+    let fields = table + mem::size_of::<SdtHeader>();
+    let address_space: u8 = read_phys(fields + 4u64);
+    if address_space != GAS_SYSTEM_MEMORY {
+        return None;
+    }
+    Some(HpetInfo {
+        address: PhysAddr::new(read_phys(fields + 8u64)),
+        number: read_phys(fields + 16u64),
+        min_tick: read_phys(fields + 17u64),
+    })
+}
+
+#[test_case]
+fn test_find_rsdp() {
+    // QEMU's firmware always provides ACPI tables
+    let rsdp = rsdp_address().expect("no RSDP found");
+    assert!(rsdp.as_u64() < 0x10_0000);
+    assert!(rsdp.is_aligned(16u64));
+}
+
+#[test_case]
+fn test_parse_madt() {
+    let madt = madt().expect("no MADT found");
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xfee0_0000));
+    let io_apic = madt.io_apics[0].expect("no I/O APIC found");
+    assert_eq!(io_apic.gsi_base, 0);
+    // The keyboard is never remapped
+    assert_eq!(madt.isa_irq(1).0, 1);
+}
+
+#[test_case]
+fn test_parse_hpet() {
+    // QEMU places the HPET at the usual address
+    let hpet = hpet().expect("no HPET found");
+    assert_eq!(hpet.address, PhysAddr::new(0xfed0_0000));
+    assert_eq!(hpet.number, 0);
+}
@@ -0,0 +1,144 @@
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use crate::memory::lazy::{self, LazyRegionError};
+
+pub mod bump;
+pub mod linked_list;
+pub mod fixed_size_block;
+
+#[cfg(feature = "alloc-bump")]
+use bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+use linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+use fixed_size_block::FixedSizeBlockAllocator;
+
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block"
+)))]
+compile_error!("one of the `alloc-bump`, `alloc-linked-list` or `alloc-fixed-block` features must be enabled");
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block")
+))]
+compile_error!("only one heap allocator feature can be enabled, use `--no-default-features` to select another one");
+
+/// Virtual start address of the kernel heap, any unused address range
+/// works as long as it does not overlap with other mappings.
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Size of the kernel heap (100 KiB)
+pub const HEAP_SIZE: usize = 100 * 1024;
+
+/// Number of bytes currently allocated from the heap, kept up to date by
+/// the `GlobalAlloc` implementations.
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+/// Set by `init_heap` once the heap is usable.
+static HEAP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Tell the compiler which allocator instance it should use as the
+// global heap allocator, it is used by the `alloc` crate for Box,
+// Vec, String and the collections. The design is selected by the
+// `alloc-*` cargo features.
+#[cfg(feature = "alloc-bump")]
+#[global_allocator]
+static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+
+#[cfg(feature = "alloc-linked-list")]
+#[global_allocator]
+static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+
+#[cfg(feature = "alloc-fixed-block")]
+#[global_allocator]
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> =
+    Locked::new(FixedSizeBlockAllocator::new());
+
+/// A wrapper around spin::Mutex to permit trait implementations, since
+/// `GlobalAlloc` can not be implemented for `spin::Mutex` outside of
+/// the spin crate.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+/// Usage of the kernel heap, returned by `heap_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Size of the heap in bytes, 0 before `init_heap`
+    pub size: usize,
+    /// Bytes currently allocated
+    pub used: usize,
+    /// Bytes not allocated. Padding and allocator metadata are counted as
+    /// free, so not all of it can necessarily be allocated.
+    pub free: usize,
+}
+
+/// Returns the current usage of the kernel heap.
+pub fn heap_stats() -> HeapStats {
+    let size = if HEAP_INITIALIZED.load(Ordering::Relaxed) { HEAP_SIZE } else { 0 };
+    let used = HEAP_USED.load(Ordering::Relaxed);
+    HeapStats {
+        size,
+        used,
+        free: size.saturating_sub(used),
+    }
+}
+
+/// Counts a successful allocation of `layout` in the heap statistics and
+/// passes the returned pointer through.
+fn record_alloc(ptr: *mut u8, layout: &Layout) -> *mut u8 {
+    if !ptr.is_null() {
+        HEAP_USED.fetch_add(layout.size(), Ordering::Relaxed);
+    }
+    ptr
+}
+
+/// Counts a deallocation of `layout` in the heap statistics.
+fn record_dealloc(layout: &Layout) {
+    HEAP_USED.fetch_sub(layout.size(), Ordering::Relaxed);
+}
+
+/// Align the given address `addr` upwards to alignment `align`.
+///
+/// Requires that `align` is a power of two.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Register the heap as a lazy region, so its pages are only mapped on
+/// their first access, and initialize the global allocator with the heap
+/// region.
+///
+/// The page fault handler maps the pages through the kernel's page table,
+/// so the page table and frame allocator have to be handed over to
+/// `memory` and the IDT has to be loaded before.
+pub fn init_heap() -> Result<(), LazyRegionError> {
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    lazy::register_lazy_region("kernel heap", heap_start, HEAP_SIZE as u64, flags)?;
+
+    // The allocator writes its bookkeeping into the heap, which maps
+    // the first pages already
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+    HEAP_INITIALIZED.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
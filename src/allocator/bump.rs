@@ -0,0 +1,80 @@
+use super::{Locked, align_up, record_alloc, record_dealloc};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+/// The simplest allocator design, it allocates memory linearly and only
+/// keeps track of the number of allocated bytes and the number of
+/// allocations. Memory is only freed all at once, after every allocation
+/// was deallocated.
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    /// Start address of the next allocation
+    next: usize,
+    /// Number of active allocations
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    /// Creates a new empty bump allocator.
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Initializes the bump allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// This method is unsafe because the caller must ensure that the given
+    /// memory range is unused. Also, this method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Get a mutable reference
+        let mut bump = self.lock();
+
+        let alloc_start = align_up(bump.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+
+        if alloc_end > bump.heap_end {
+            // Out of memory
+            ptr::null_mut()
+        } else {
+            bump.next = alloc_end;
+            bump.allocations += 1;
+            record_alloc(alloc_start as *mut u8, &layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
+        record_dealloc(&layout);
+        let mut bump = self.lock();
+
+        bump.allocations -= 1;
+        // Once every allocation was freed the whole heap can be
+        // reused again
+        if bump.allocations == 0 {
+            bump.next = bump.heap_start;
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+/// Processor features (leaf 1), EDX bits
+const LEAF1_EDX_APIC: u32 = 1 << 9;
+const LEAF1_EDX_PAT: u32 = 1 << 16;
+
+/// Extended processor features (leaf 0x8000_0001), EDX bits
+const EXT_EDX_PAGE_1GB: u32 = 1 << 26;
+
+/// Advanced power management (leaf 0x8000_0007), EDX bits
+const APM_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+/// Structured extended features (leaf 7, subleaf 0), EBX and ECX bits
+const LEAF7_EBX_SMEP: u32 = 1 << 7;
+const LEAF7_EBX_SMAP: u32 = 1 << 20;
+const LEAF7_ECX_UMIP: u32 = 1 << 2;
+
+/// Returns the EDX register of the extended processor features leaf, or 0
+/// if the CPU doesn't support that leaf.
+fn extended_features_edx() -> u32 {
+    // The highest supported extended leaf is reported by leaf
+    // 0x8000_0000
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0001 {
+        return 0;
+    }
+    unsafe { __cpuid(0x8000_0001) }.edx
+}
+
+/// Returns the EBX and ECX registers of the structured extended features
+/// leaf, or 0 if the CPU doesn't support that leaf.
+fn structured_extended_features() -> (u32, u32) {
+    // The highest supported basic leaf is reported by leaf 0
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 7 {
+        return (0, 0);
+    }
+    let result = unsafe { __cpuid_count(7, 0) };
+    (result.ebx, result.ecx)
+}
+
+/// Returns `true` if the time stamp counter runs at a constant rate in all
+/// power and performance states, so it can be used as a clock.
+pub fn has_invariant_tsc() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & APM_EDX_INVARIANT_TSC != 0
+}
+
+/// Returns `true` if the CPU supports 1 GiB pages. 2 MiB pages are always
+/// supported in long mode.
+pub fn has_1gib_pages() -> bool {
+    extended_features_edx() & EXT_EDX_PAGE_1GB != 0
+}
+
+/// Returns `true` if the CPU has a local APIC.
+pub fn has_apic() -> bool {
+    unsafe { __cpuid(1) }.edx & LEAF1_EDX_APIC != 0
+}
+
+/// Returns `true` if the CPU supports the page attribute table.
+pub fn has_pat() -> bool {
+    unsafe { __cpuid(1) }.edx & LEAF1_EDX_PAT != 0
+}
+
+/// Returns `true` if the CPU supports supervisor mode execution prevention.
+pub fn has_smep() -> bool {
+    structured_extended_features().0 & LEAF7_EBX_SMEP != 0
+}
+
+/// Returns `true` if the CPU supports supervisor mode access prevention.
+pub fn has_smap() -> bool {
+    structured_extended_features().0 & LEAF7_EBX_SMAP != 0
+}
+
+/// Returns `true` if the CPU supports user mode instruction prevention.
+pub fn has_umip() -> bool {
+    structured_extended_features().1 & LEAF7_ECX_UMIP != 0
+}
+
+/// The protections against user space that are enabled in CR4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protections {
+    /// The kernel can't execute code on user accessible pages
+    pub smep: bool,
+    /// The kernel can't access user accessible pages outside of
+    /// `with_user_access`
+    pub smap: bool,
+    /// User space can't read the descriptor table registers (`sgdt`,
+    /// `sidt`, ...)
+    pub umip: bool,
+}
+
+impl Protections {
+    /// Returns the protections that are currently enabled.
+    pub fn enabled() -> Self {
+        let cr4 = Cr4::read();
+        Protections {
+            smep: cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+            smap: cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+            umip: cr4.contains(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION),
+        }
+    }
+}
+
+impl fmt::Display for Protections {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [(self.smep, "SMEP"), (self.smap, "SMAP"), (self.umip, "UMIP")];
+        let mut enabled = names.iter().filter(|(on, _)| *on).map(|(_, name)| name);
+        match enabled.next() {
+            Some(first) => {
+                write!(f, "{}", first)?;
+                for name in enabled {
+                    write!(f, " {}", name)?;
+                }
+                Ok(())
+            }
+            None => write!(f, "none"),
+        }
+    }
+}
+
+/// Enables every protection against user space the CPU supports and
+/// returns the enabled protections.
+pub fn harden() -> Protections {
+    let mut flags = Cr4Flags::empty();
+    flags.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, has_smep());
+    flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, has_smap());
+    flags.set(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION, has_umip());
+
+    // No kernel page is user accessible, so none of the protections
+    // affect the kernel itself
+    unsafe {
+        Cr4::update(|cr4| cr4.insert(flags));
+    }
+
+    Protections::enabled()
+}
+
+/// Runs `f` with supervisor mode access prevention temporarily lifted, so
+/// it can access user accessible pages.
+///
+/// Interrupt handlers inherit the lifted protection, so `f` should be as
+/// short as possible.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    // `stac` and `clac` are invalid opcodes without SMAP support
+    let smap = Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+
+    // No `nomem`, the compiler must not move memory accesses out of
+    // the guarded section
+    if smap {
+        unsafe { core::arch::asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { core::arch::asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+#[test_case]
+fn test_protections_match_cpu_support() {
+    // `init` enabled everything the CPU supports
+    let enabled = Protections::enabled();
+    assert_eq!(enabled.smep, has_smep());
+    assert_eq!(enabled.smap, has_smap());
+    assert_eq!(enabled.umip, has_umip());
+}
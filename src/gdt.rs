@@ -1,49 +1,83 @@
+use core::ptr::{addr_of, addr_of_mut};
 use x86_64::VirtAddr;
 use x86_64::registers::segmentation::SegmentSelector;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB, mapper::MapToError};
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
+use spin::Once;
+use crate::memory::stack::{self, Stack};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+/// Number of interrupt stacks the kernel uses.
+const IST_STACK_COUNT: usize = 4;
+
+/// Number of nested page faults that get their own part of the page fault
+/// stack, the page fault handler can fault again while it maps lazy or
+/// copy-on-write pages.
+const PAGE_FAULT_NESTING: u64 = 2;
+
+/// Sizes of the interrupt stacks in pages.
+#[derive(Debug, Clone, Copy)]
+pub struct IstStackSizes {
+    pub double_fault: u64,
+    pub nmi: u64,
+    pub machine_check: u64,
+    pub page_fault: u64,
+}
+
+impl Default for IstStackSizes {
+    fn default() -> Self {
+        IstStackSizes {
+            double_fault: 5,
+            nmi: 4,
+            machine_check: 4,
+            page_fault: 8,
+        }
+    }
+}
 
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+// The TSS is not part of the lazy_static since its interrupt stack table
+// is filled in by `init_stacks` once the memory management is up.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// The interrupt stacks, indexed by their interrupt stack table index.
+static IST_STACKS: Once<[Stack; IST_STACK_COUNT]> = Once::new();
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
 
         // Add code segment
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        // add TSS segment
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // add TSS segment, the descriptor only keeps the address of
+        // the TSS, so the reference doesn't outlive this call
+        let tss = unsafe { &*addr_of!(TSS) };
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
 
         (gdt, Selectors { code_selector, tss_selector })
     };
-    
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-
-            stack_end
-        };
-
-        tss
-    };
 }
 
+/// Load the GDT and TSS.
+///
+/// Panics if `init_stacks` was not called before, the IDT entries rely on
+/// the interrupt stacks being present.
 pub fn init() {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, Segment};
 
+    assert!(IST_STACKS.is_completed(), "interrupt stacks are not initialized");
+
     GDT.0.load();
 
     // Reload the cs register and load our TSS
@@ -51,4 +85,119 @@ pub fn init() {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+/// Allocate the interrupt stacks from the page allocator, each of them is
+/// protected by a guard page. Must be called once, before `init`.
+pub fn init_stacks(
+    sizes: IstStackSizes,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut allocate = |name, pages| stack::allocate_stack(name, pages, mapper, frame_allocator);
+    // Same order as the IST indices
+    let stacks = [
+        allocate("double fault stack", sizes.double_fault)?,
+        allocate("NMI stack", sizes.nmi)?,
+        allocate("machine check stack", sizes.machine_check)?,
+        allocate("page fault stack", sizes.page_fault)?,
+    ];
+
+    let stacks = IST_STACKS.call_once(|| stacks);
+    for (index, stack) in stacks.iter().enumerate() {
+        unsafe {
+            TSS.interrupt_stack_table[index] = stack.top();
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the interrupt stack with the given interrupt stack table index.
+pub fn ist_stack(index: u16) -> Option<Stack> {
+    IST_STACKS.get()?.get(index as usize).copied()
+}
+
+/// Returns the interrupt stack table index of the interrupt stack that
+/// contains `addr`, or `None` if it isn't on an interrupt stack.
+pub fn ist_index_of(addr: VirtAddr) -> Option<u16> {
+    IST_STACKS
+        .get()?
+        .iter()
+        .position(|stack| stack.contains(addr))
+        .map(|index| index as u16)
+}
+
+/// The part of the page fault stack used by the running page fault
+/// handler, see `reserve_page_fault_stack`.
+pub struct PageFaultStackReservation {
+    previous_top: Option<VirtAddr>,
+}
+
+/// Moves the page fault entry of the interrupt stack table below the part
+/// of the page fault stack that the running handler uses, so a nested page
+/// fault starts on a fresh part instead of overwriting the frames of the
+/// running handler. The entry is restored when the reservation is dropped.
+///
+/// Must be called first thing by the page fault handler, with interrupts
+/// disabled. The stack is split into `PAGE_FAULT_NESTING` parts, the
+/// innermost handler moves the entry to the bottom of the stack, so another
+/// fault hits the guard page and becomes a double fault.
+pub fn reserve_page_fault_stack() -> PageFaultStackReservation {
+    let stack = match ist_stack(PAGE_FAULT_IST_INDEX) {
+        Some(stack) => stack,
+        None => return PageFaultStackReservation { previous_top: None },
+    };
+    let part = ((stack.top() - stack.bottom()) / PAGE_FAULT_NESTING) & !0xf;
+    // The CPU only reads the table when it switches stacks, which can't
+    // happen concurrently with interrupts disabled
+    unsafe {
+        let tss = &mut *addr_of_mut!(TSS);
+        let previous_top = tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize];
+        let nested_top = VirtAddr::new(previous_top.as_u64().saturating_sub(part));
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = nested_top.max(stack.bottom());
+        PageFaultStackReservation { previous_top: Some(previous_top) }
+    }
+}
+
+impl Drop for PageFaultStackReservation {
+    fn drop(&mut self) {
+        if let Some(previous_top) = self.previous_top {
+            unsafe {
+                let tss = &mut *addr_of_mut!(TSS);
+                tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = previous_top;
+            }
+        }
+    }
+}
+
+/// Returns the current value of the stack pointer.
+#[inline(always)]
+pub fn stack_pointer() -> VirtAddr {
+    let rsp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    VirtAddr::new(rsp)
+}
+
+#[test_case]
+fn test_nested_page_faults_use_separate_stack_parts() {
+    let stack = ist_stack(PAGE_FAULT_IST_INDEX).expect("no page fault stack");
+    let entry = || unsafe {
+        (*addr_of!(TSS)).interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize]
+    };
+    assert_eq!(entry(), stack.top());
+
+    let outer = reserve_page_fault_stack();
+    let nested_top = entry();
+    assert!(nested_top < stack.top() && nested_top > stack.bottom());
+    let inner = reserve_page_fault_stack();
+    // A third fault would run into the guard page
+    assert_eq!(entry(), stack.bottom());
+
+    drop(inner);
+    assert_eq!(entry(), nested_top);
+    drop(outer);
+    assert_eq!(entry(), stack.top());
+}
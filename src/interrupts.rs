@@ -1,7 +1,22 @@
 use pic8259::ChainedPics;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::registers::control::Cr2;
 use lazy_static::lazy_static;
-use crate::{println, print, gdt, hlt_loop};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{println, print, gdt};
+use crate::memory::{cow, lazy, stack};
+
+pub mod apic;
+pub mod exceptions;
+mod irq;
+mod stats;
+
+pub use exceptions::{set_crash_handler, CrashHandler, CrashReport};
+pub use irq::{
+    is_registered, register_irq, spurious_irqs, unregister_irq, IrqError, IrqHandler,
+    SpuriousIrqs, IRQ_COUNT,
+};
+pub use stats::{interrupt_count, irqstat, vector_name};
 
 /// The default configuration of the PICs is not usable because it sends interrupt
 /// vector numbers in the range of 0–15 to the CPU. These numbers are already 
@@ -28,23 +43,62 @@ pub enum InterruptIndex {
     Keyboard,
 }
 
+impl InterruptIndex {
+    /// The IRQ line of the interrupt.
+    pub fn irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
+        idt.debug.set_handler_fn(debug_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // Everything else the CPU can raise is fatal and reported the same
+        // way, so an exception never ends up at a missing IDT entry
+        idt.divide_error.set_handler_fn(exceptions::divide_error_handler);
+        idt.overflow.set_handler_fn(exceptions::overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(exceptions::bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(exceptions::invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(exceptions::device_not_available_handler);
+        idt.invalid_tss.set_handler_fn(exceptions::invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(exceptions::segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(exceptions::stack_segment_fault_handler);
+        idt.general_protection_fault
+            .set_handler_fn(exceptions::general_protection_fault_handler);
+        idt.x87_floating_point.set_handler_fn(exceptions::x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(exceptions::alignment_check_handler);
+        idt.simd_floating_point.set_handler_fn(exceptions::simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(exceptions::virtualization_handler);
+        idt.vmm_communication_exception
+            .set_handler_fn(exceptions::vmm_communication_exception_handler);
+        idt.security_exception.set_handler_fn(exceptions::security_exception_handler);
+        // Exceptions that can occur while the current stack is unusable
+        // run on their own interrupt stacks
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 // set the stack the double_fault exception will use
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            // The handler moves the stack table entry while it runs, so
+            // nested page faults don't reuse its part of the stack
+            idt.page_fault.set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        // Add handler function for the timer interrupt
-        idt[InterruptIndex::Timer as usize]
-            .set_handler_fn(timer_interrupt_handler);
-        // Add handler function for keyboard interrupt
-        idt[InterruptIndex::Keyboard as usize]
-            .set_handler_fn(keyboard_interrupt_handler);
+        // Every IRQ line goes through a trampoline that calls the handler
+        // registered for it at runtime
+        for (irq, trampoline) in irq::TRAMPOLINES.iter().enumerate() {
+            idt[PIC_1_OFFSET as usize + irq].set_handler_fn(*trampoline);
+        }
+        // The local APIC raises this vector instead of an interrupt that
+        // vanished before it could be delivered
+        idt[apic::SPURIOUS_VECTOR as usize]
+            .set_handler_fn(apic_spurious_interrupt_handler);
 
         idt
     };
@@ -59,52 +113,179 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// The interrupt controllers that can deliver the hardware interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The two chained 8259 PICs
+    Pic,
+    /// The local APIC together with an I/O APIC
+    Apic,
+}
+
+/// Set once the hardware interrupts are delivered by the APICs.
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the `preferred` interrupt controller and returns the one that
+/// is used. Falls back to the PICs if no APIC is found.
+///
+/// All IRQ lines start out masked, then the timer and keyboard handlers
+/// are registered.
+pub fn init_controller(preferred: InterruptController) -> InterruptController {
+    // The PICs are remapped in any case, even when masked they can
+    // raise spurious interrupts
+    unsafe { PICS.lock().initialize() };
+    irq::mask_all_pic_irqs();
+
+    if preferred == InterruptController::Apic {
+        match apic::init() {
+            Ok(()) => APIC_ENABLED.store(true, Ordering::SeqCst),
+            Err(err) => println!("APIC not usable ({:?}), using the PIC", err),
+        }
+    }
+
+    register_irq(InterruptIndex::Timer.irq(), timer_interrupt_handler)
+        .expect("timer IRQ handler already registered");
+    register_irq(InterruptIndex::Keyboard.irq(), keyboard_interrupt_handler)
+        .expect("keyboard IRQ handler already registered");
+    controller()
+}
+
+/// Returns the interrupt controller that delivers the hardware interrupts.
+pub fn controller() -> InterruptController {
+    if APIC_ENABLED.load(Ordering::SeqCst) {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic
+    }
+}
+
+/// Notifies the interrupt controller that the interrupt of the IRQ line was
+/// processed and that the system is ready to receive the next one. Called
+/// by the dispatcher, registered handlers don't have to.
+fn end_of_interrupt(irq: u8) {
+    match controller() {
+        InterruptController::Apic => apic::end_of_interrupt(),
+        InterruptController::Pic => unsafe {
+            PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+        },
+    }
+}
+
+/// Stack pointer of the last handler that recorded it, so tests can check
+/// which stack a handler ran on.
+#[cfg(test)]
+static HANDLER_STACK_POINTER: core::sync::atomic::AtomicU64 =
+    core::sync::atomic::AtomicU64::new(0);
+
 /// Handler for the breakpoint exception, pause a program when the breakpoint
 /// instruction int3 is executed.
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame
 ) {
+    stats::record(3);
+
+    #[cfg(test)]
+    HANDLER_STACK_POINTER.store(
+        gdt::stack_pointer().as_u64(),
+        core::sync::atomic::Ordering::SeqCst,
+    );
+
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+/// Handler for the debug exception, raised by hardware breakpoints and
+/// single stepping.
+extern "x86-interrupt" fn debug_handler(
+    stack_frame: InterruptStackFrame
+) {
+    stats::record(1);
+    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn nmi_handler(
+    stack_frame: InterruptStackFrame
+) {
+    stats::record(2);
+
+    #[cfg(test)]
+    HANDLER_STACK_POINTER.store(
+        gdt::stack_pointer().as_u64(),
+        core::sync::atomic::Ordering::SeqCst,
+    );
+
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame
+) -> ! {
+    stats::record(18);
+    exceptions::crash(&CrashReport::new(18, None, *stack_frame));
+}
+
 extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64
+    stack_frame: InterruptStackFrame, error_code: u64
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    stats::record(8);
+
+    let mut report = CrashReport::new(
+        8,
+        Some(exceptions::ErrorCode::Raw(error_code)),
+        *stack_frame,
+    );
+    // Overflows are reported by the page fault handler, unless it
+    // can't run because the page fault stack itself is exhausted, the
+    // CPU raises a double fault instead. CR2 still holds the address
+    // of the failed access in this case.
+    report.overflowed_stack = stack::overflowed_stack(Cr2::read()).map(|stack| stack.name());
+    exceptions::crash(&report);
 }
 
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::Cr2;
+    // Resolving the fault can fault again, e.g. on a lazy page table
+    // page, the nested fault has to start below this handler's frames
+    let _stack = gdt::reserve_page_fault_stack();
+    stats::record(14);
+
+    #[cfg(test)]
+    HANDLER_STACK_POINTER.store(
+        gdt::stack_pointer().as_u64(),
+        core::sync::atomic::Ordering::SeqCst,
+    );
+
+    // Pages of lazy regions are mapped on their first access, the
+    // faulting instruction is restarted after returning
+    if lazy::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+    // Writes to copy-on-write pages get their own copy of the frame
+    if cow::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
 
-    println!("EXCEPTION: PAGE FAULT");
-    // The CR2 register is automatically set by the CPU on a 
+    // The CR2 register is automatically set by the CPU on a
     // page fault and contains the accessed virtual address that
-    // caused the page fault.
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    // caused the page fault, the report includes it.
+    let mut report = CrashReport::new(
+        14,
+        Some(exceptions::ErrorCode::PageFault(error_code)),
+        *stack_frame,
+    );
+    // Accesses to the guard page below a kernel stack mean that
+    // the stack overflowed, the handler still runs since it has
+    // its own stack
+    report.overflowed_stack = stack::overflowed_stack(Cr2::read()).map(|stack| stack.name());
+    exceptions::crash(&report);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame
-) {
-    print!(".");
-
-    // Notify the controller that the interrupt was processed and that the system
-    // is ready to recieve the next interrupt.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
-    }
+fn timer_interrupt_handler(_irq: u8) {
+    crate::time::tick();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame
-) {
+fn keyboard_interrupt_handler(_irq: u8) {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -132,16 +313,59 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
             }
         }
     }
+}
 
-    // Notify the controller that the interrupt was processed and that the system
-    // is ready to recieve the next interrupt.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
-    }
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame
+) {
+    stats::record(apic::SPURIOUS_VECTOR);
+    // Spurious interrupts are not acknowledged, only counted
+    irq::count_apic_spurious();
 }
 
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_breakpoint_runs_on_current_stack() {
+    use core::sync::atomic::Ordering;
+    use x86_64::VirtAddr;
+
+    x86_64::instructions::interrupts::int3();
+    let rsp = VirtAddr::new(HANDLER_STACK_POINTER.load(Ordering::SeqCst));
+    assert_eq!(gdt::ist_index_of(rsp), None);
+}
+
+#[test_case]
+fn test_nmi_runs_on_nmi_stack() {
+    use core::sync::atomic::Ordering;
+    use x86_64::VirtAddr;
+
+    // A software interrupt to the NMI vector goes through the same
+    // IDT entry, so it switches to the NMI stack as well
+    unsafe { core::arch::asm!("int 2") };
+    let rsp = VirtAddr::new(HANDLER_STACK_POINTER.load(Ordering::SeqCst));
+    assert_eq!(gdt::ist_index_of(rsp), Some(gdt::NMI_IST_INDEX));
+    let nmi_stack = gdt::ist_stack(gdt::NMI_IST_INDEX).expect("no NMI stack");
+    assert!(nmi_stack.contains(rsp));
+}
+
+#[test_case]
+fn test_page_fault_runs_on_page_fault_stack() {
+    use core::sync::atomic::Ordering;
+    use crate::memory::lazy;
+    use x86_64::VirtAddr;
+    use x86_64::structures::paging::PageTableFlags;
+
+    let start = VirtAddr::new(0x_6666_2000_0000);
+    lazy::register_lazy_region("fault stack test", start, 4096, PageTableFlags::WRITABLE)
+        .expect("registering the lazy region failed");
+    // The first access faults and is resolved by mapping the page
+    let ptr: *mut u64 = start.as_mut_ptr();
+    unsafe { ptr.write_volatile(1) };
+    let rsp = VirtAddr::new(HANDLER_STACK_POINTER.load(Ordering::SeqCst));
+    assert_eq!(gdt::ist_index_of(rsp), Some(gdt::PAGE_FAULT_IST_INDEX));
+    unsafe { lazy::unregister_lazy_region(start) };
+}
@@ -0,0 +1,281 @@
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+use crate::acpi::{self, Madt};
+use crate::cpu;
+use crate::memory::mmio::{self, CacheMode, Mmio};
+use crate::memory::vmalloc::VmapError;
+use super::PIC_1_OFFSET;
+
+/// MSR holding the physical base address of the local APIC.
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Global enable bit of the local APIC in `IA32_APIC_BASE`.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// Bits 12 to 51 of `IA32_APIC_BASE` hold the base address.
+const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Local APIC register offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+/// Size of the local APIC register page that is used
+const LAPIC_SIZE: usize = 0x400;
+
+/// Software enable bit of the spurious interrupt vector register.
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// Vector the local APIC uses for spurious interrupts, they must not be
+/// acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// I/O APIC registers, accessed indirectly through the select and
+/// window registers
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+/// Offsets of the select and window registers in `u32`s
+const IOREGSEL: usize = 0;
+const IOWIN: usize = 4;
+
+/// Redirection entry bits
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+/// The local APIC of the CPU, set by `init`.
+static LOCAL_APIC: Mutex<Option<LocalApic>> = Mutex::new(None);
+
+/// The I/O APIC the legacy ISA interrupts are routed through, set by
+/// `init`.
+static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+
+/// Errors that can occur when switching to the APICs.
+#[derive(Debug)]
+pub enum ApicError {
+    /// The CPU has no local APIC
+    Unsupported,
+    /// The ACPI tables contain no MADT
+    NoMadt,
+    /// The MADT describes no I/O APIC
+    NoIoApic,
+    /// Mapping the APIC registers failed
+    Map(VmapError),
+    /// The global system interrupt is not handled by the I/O APIC
+    UnroutableGsi(u32),
+}
+
+/// The memory mapped registers of the local APIC.
+struct LocalApic {
+    regs: Mmio<u32>,
+}
+
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        self.regs[reg / 4].read()
+    }
+
+    fn write(&mut self, reg: usize, value: u32) {
+        self.regs[reg / 4].write(value);
+    }
+
+    /// The APIC ID, used as the destination of redirected interrupts.
+    fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+}
+
+/// The memory mapped registers of an I/O APIC.
+struct IoApic {
+    regs: Mmio<u32>,
+    gsi_base: u32,
+    /// Number of redirection entries, read from the version register
+    entries: u32,
+    /// The interrupt source overrides of the ISA IRQs
+    madt: Madt,
+    /// APIC ID of the local APIC the interrupts are sent to
+    destination: u8,
+}
+
+impl IoApic {
+    fn read(&mut self, reg: u32) -> u32 {
+        self.regs[IOREGSEL].write(reg);
+        self.regs[IOWIN].read()
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        self.regs[IOREGSEL].write(reg);
+        self.regs[IOWIN].write(value);
+    }
+
+    /// Number of interrupts the I/O APIC handles.
+    fn redirection_entries(&mut self) -> u32 {
+        ((self.read(IOAPIC_VER) >> 16) & 0xff) + 1
+    }
+
+    /// Returns the register of the redirection entry of the global system
+    /// interrupt, if the I/O APIC handles it.
+    fn redirection_register(&self, gsi: u32) -> Result<u32, ApicError> {
+        match gsi.checked_sub(self.gsi_base) {
+            Some(index) if index < self.entries => Ok(IOAPIC_REDTBL + 2 * index),
+            _ => Err(ApicError::UnroutableGsi(gsi)),
+        }
+    }
+
+    /// Writes the redirection entry of the global system interrupt.
+    fn set_redirection(&mut self, gsi: u32, entry: u64) -> Result<(), ApicError> {
+        let reg = self.redirection_register(gsi)?;
+        // The upper half holds the destination, write it while the
+        // entry might still be masked
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+        Ok(())
+    }
+
+    /// Routes the ISA `irq` to its vector above the exception vectors,
+    /// honoring the interrupt source overrides of the MADT.
+    fn route_isa_irq(&mut self, irq: u8, masked: bool) -> Result<(), ApicError> {
+        let (gsi, interrupt_override) = self.madt.isa_irq(irq);
+        let vector = PIC_1_OFFSET + irq;
+        let mut entry = vector as u64 | (self.destination as u64) << 56;
+        if let Some(interrupt_override) = interrupt_override {
+            if interrupt_override.active_low {
+                entry |= REDIRECT_ACTIVE_LOW;
+            }
+            if interrupt_override.level_triggered {
+                entry |= REDIRECT_LEVEL;
+            }
+        }
+        if masked {
+            entry |= REDIRECT_MASKED;
+        }
+        self.set_redirection(gsi, entry)
+    }
+
+    /// Reads the redirection entry of the global system interrupt.
+    #[cfg(test)]
+    fn redirection(&mut self, gsi: u32) -> Result<u64, ApicError> {
+        let reg = self.redirection_register(gsi)?;
+        Ok((self.read(reg + 1) as u64) << 32 | self.read(reg) as u64)
+    }
+}
+
+/// Returns `true` if the CPU has a local APIC and the ACPI tables describe
+/// an I/O APIC.
+pub fn is_available() -> bool {
+    cpu::has_apic() && acpi::madt().is_some_and(|madt| madt.io_apics[0].is_some())
+}
+
+/// Enables the local APIC and the I/O APIC with all interrupts masked, then
+/// masks the legacy PICs. The ISA IRQs are unmasked by
+/// `set_isa_irq_masked` once a handler is registered.
+///
+/// The PICs must have been remapped before, so spurious interrupts they
+/// raise while being masked don't hit exception vectors. Needs the memory
+/// management to map the registers.
+pub fn init() -> Result<(), ApicError> {
+    if !cpu::has_apic() {
+        return Err(ApicError::Unsupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+    let io_apic_info = madt.io_apics[0].ok_or(ApicError::NoIoApic)?;
+
+    // The base address in the MSR takes precedence over the MADT, they
+    // only differ if the firmware relocated the APIC
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base_value = unsafe { base_msr.read() };
+    let base = PhysAddr::new(base_value & APIC_BASE_MASK);
+
+    // Both register blocks are mapped before the local APIC is enabled,
+    // so a failed mapping leaves the PICs in charge and the APIC as the
+    // firmware left it
+    let local_apic_regs = unsafe { mmio::map_mmio(base, LAPIC_SIZE, CacheMode::Uncacheable) }
+        .map_err(ApicError::Map)?;
+    let io_apic_regs =
+        unsafe { mmio::map_mmio(io_apic_info.address, 0x20, CacheMode::Uncacheable) }
+            .map_err(ApicError::Map)?;
+
+    unsafe { base_msr.write(base_value | APIC_BASE_ENABLE) };
+    let mut local_apic = LocalApic { regs: local_apic_regs };
+    // Accept all interrupts and enable the APIC
+    local_apic.write(LAPIC_TPR, 0);
+    local_apic.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    let mut io_apic = IoApic {
+        regs: io_apic_regs,
+        gsi_base: io_apic_info.gsi_base,
+        entries: 0,
+        madt,
+        destination: local_apic.id(),
+    };
+    io_apic.entries = io_apic.redirection_entries();
+    for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+        io_apic
+            .set_redirection(gsi, REDIRECT_MASKED)
+            .expect("GSI of the I/O APIC out of range");
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *LOCAL_APIC.lock() = Some(local_apic);
+        *IO_APIC.lock() = Some(io_apic);
+        if madt.has_legacy_pics {
+            unsafe { super::PICS.lock().disable() };
+        }
+    });
+
+    Ok(())
+}
+
+/// Masks or unmasks the ISA `irq` at the I/O APIC. Does nothing if the
+/// APICs are not initialized, fails if the IRQ is routed to a global system
+/// interrupt the I/O APIC doesn't handle.
+pub fn set_isa_irq_masked(irq: u8, masked: bool) -> Result<(), ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        match IO_APIC.lock().as_mut() {
+            Some(io_apic) => io_apic.route_isa_irq(irq, masked),
+            None => Ok(()),
+        }
+    })
+}
+
+/// Acknowledges the interrupt that is currently handled.
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = LOCAL_APIC.lock().as_mut() {
+        local_apic.write(LAPIC_EOI, 0);
+    }
+}
+
+#[test_case]
+fn test_apic_routing() {
+    if !is_available() {
+        return;
+    }
+    assert_eq!(super::controller(), super::InterruptController::Apic);
+
+    // The interrupt handlers take the lock of the local APIC
+    let svr = x86_64::instructions::interrupts::without_interrupts(|| {
+        LOCAL_APIC.lock().as_ref().expect("local APIC not initialized").read(LAPIC_SVR)
+    });
+    assert_eq!(svr, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    // The keyboard is routed unmasked, everything unused stays masked
+    let madt = acpi::madt().expect("no MADT found");
+    let mut io_apic = IO_APIC.lock();
+    let io_apic = io_apic.as_mut().expect("I/O APIC not initialized");
+    let keyboard = io_apic.redirection(madt.isa_irq(1).0).expect("keyboard not routed");
+    assert_eq!(keyboard & 0xff, super::InterruptIndex::Keyboard as u64);
+    assert_eq!(keyboard & REDIRECT_MASKED, 0);
+    let last = io_apic.gsi_base + io_apic.entries - 1;
+    assert_ne!(io_apic.redirection(last).expect("last GSI not handled") & REDIRECT_MASKED, 0);
+
+    // Global system interrupts of other I/O APICs are rejected
+    assert!(matches!(
+        io_apic.redirection(last + 1),
+        Err(ApicError::UnroutableGsi(gsi)) if gsi == last + 1
+    ));
+
+    // The legacy PICs are masked completely
+    let masks = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        super::PICS.lock().read_masks()
+    });
+    assert_eq!(masks, [0xff, 0xff]);
+}
@@ -0,0 +1,213 @@
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{
+    InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode, SelectorErrorCode,
+};
+use crate::{println, serial_println};
+
+/// The error code an exception pushed, decoded according to the exception.
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// The segment selector that caused the exception, or null if the
+    /// exception was not caused by a selector
+    Selector(SelectorErrorCode),
+    /// The kind of access that caused a page fault
+    PageFault(PageFaultErrorCode),
+    /// An error code without further meaning, usually 0
+    Raw(u64),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::Selector(selector) if selector.is_null() => write!(f, "none (null selector)"),
+            ErrorCode::Selector(selector) => write!(
+                f,
+                "selector {} in the {:?}{}",
+                selector.index(),
+                selector.descriptor_table(),
+                if selector.external() { ", external event" } else { "" }
+            ),
+            ErrorCode::PageFault(error_code) => write!(f, "{:?}", error_code),
+            ErrorCode::Raw(error_code) => write!(f, "{:#x}", error_code),
+        }
+    }
+}
+
+/// Everything known about an exception the kernel can't recover from.
+#[derive(Clone, Copy)]
+pub struct CrashReport {
+    pub vector: u8,
+    /// Name of the exception, e.g. "INVALID OPCODE"
+    pub name: &'static str,
+    pub error_code: Option<ErrorCode>,
+    pub stack_frame: InterruptStackFrameValue,
+    /// Name of the kernel stack whose guard page was hit, if the exception
+    /// was caused by a stack overflow
+    pub overflowed_stack: Option<&'static str>,
+    pub cr0: u64,
+    /// The address of the last page fault
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl CrashReport {
+    /// Creates a report of the exception, the control registers are read
+    /// at the time of the call.
+    pub fn new(
+        vector: u8,
+        error_code: Option<ErrorCode>,
+        stack_frame: InterruptStackFrameValue,
+    ) -> Self {
+        CrashReport {
+            vector,
+            name: super::vector_name(vector),
+            error_code,
+            stack_frame,
+            overflowed_stack: None,
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "EXCEPTION: {} (vector {})", self.name, self.vector)?;
+        if let Some(error_code) = self.error_code {
+            writeln!(f, "Error Code: {}", error_code)?;
+        }
+        if let Some(stack) = self.overflowed_stack {
+            writeln!(f, "stack overflow in {}", stack)?;
+        }
+        writeln!(
+            f,
+            "CR0: {:#x}  CR2: {:#x}  CR3: {:#x}  CR4: {:#x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )?;
+        write!(f, "{:#?}", self.stack_frame)
+    }
+}
+
+/// A function that is called with the report of a fatal exception after
+/// it was printed.
+pub type CrashHandler = fn(&CrashReport) -> !;
+
+/// The handler set by `set_crash_handler`, null if the default is used.
+static CRASH_HANDLER: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Replaces what happens after a fatal exception was reported, by default
+/// the kernel panics.
+pub fn set_crash_handler(handler: CrashHandler) {
+    CRASH_HANDLER.store(handler as *mut (), Ordering::Release);
+}
+
+/// Prints the report on the screen and the serial port and hands it to
+/// the crash handler.
+pub fn crash(report: &CrashReport) -> ! {
+    println!("{}", report);
+    serial_println!("{}", report);
+
+    let handler = CRASH_HANDLER.load(Ordering::Acquire);
+    if !handler.is_null() {
+        let handler: CrashHandler = unsafe { core::mem::transmute(handler) };
+        handler(report);
+    }
+    panic!("fatal exception: {}", report.name);
+}
+
+/// Defines handlers for exceptions that are always fatal, they count and
+/// report the exception with `crash`. Exceptions pushing an error code
+/// name the `ErrorCode` variant it is decoded into.
+macro_rules! fatal_exceptions {
+    ($($handler:ident: $vector:literal $(($decode:ident))?;)*) => {
+        $(fatal_exceptions!(@handler $handler, $vector $(, $decode)?);)*
+    };
+    (@handler $handler:ident, $vector:literal) => {
+        pub(super) extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            super::stats::record($vector);
+            crash(&CrashReport::new($vector, None, *stack_frame));
+        }
+    };
+    (@handler $handler:ident, $vector:literal, $decode:ident) => {
+        pub(super) extern "x86-interrupt" fn $handler(
+            stack_frame: InterruptStackFrame,
+            error_code: u64,
+        ) {
+            super::stats::record($vector);
+            let error_code = fatal_exceptions!(@decode $decode, error_code);
+            crash(&CrashReport::new($vector, Some(error_code), *stack_frame));
+        }
+    };
+    (@decode Selector, $error_code:ident) => {
+        ErrorCode::Selector(SelectorErrorCode::new_truncate($error_code))
+    };
+    (@decode Raw, $error_code:ident) => {
+        ErrorCode::Raw($error_code)
+    };
+}
+
+fatal_exceptions! {
+    divide_error_handler: 0;
+    overflow_handler: 4;
+    bound_range_exceeded_handler: 5;
+    invalid_opcode_handler: 6;
+    device_not_available_handler: 7;
+    invalid_tss_handler: 10 (Selector);
+    segment_not_present_handler: 11 (Selector);
+    stack_segment_fault_handler: 12 (Selector);
+    general_protection_fault_handler: 13 (Selector);
+    x87_floating_point_handler: 16;
+    alignment_check_handler: 17 (Raw);
+    simd_floating_point_handler: 19;
+    virtualization_handler: 20;
+    vmm_communication_exception_handler: 29 (Raw);
+    security_exception_handler: 30 (Raw);
+}
+
+#[test_case]
+fn test_crash_report_format() {
+    use core::fmt::Write;
+
+    /// Collects formatted text in a fixed buffer, the heap might not be
+    /// initialized yet.
+    struct Buffer {
+        bytes: [u8; 1024],
+        len: usize,
+    }
+
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    let stack_frame = InterruptStackFrameValue {
+        instruction_pointer: x86_64::VirtAddr::new(0x6666_3000_0000),
+        code_segment: 0x08,
+        cpu_flags: 0x2,
+        stack_pointer: x86_64::VirtAddr::new(0x6666_3000_1000),
+        stack_segment: 0,
+    };
+    // A selector error code of GDT entry 3
+    let error_code = ErrorCode::Selector(SelectorErrorCode::new_truncate(3 << 3));
+    let report = CrashReport::new(13, Some(error_code), stack_frame);
+    assert_eq!(report.cr3, Cr3::read().0.start_address().as_u64());
+
+    let mut buffer = Buffer { bytes: [0; 1024], len: 0 };
+    write!(buffer, "{}", report).expect("report too long");
+    let text = core::str::from_utf8(&buffer.bytes[..buffer.len]).unwrap();
+    let mut lines = text.lines();
+    assert_eq!(lines.next(), Some("EXCEPTION: GENERAL PROTECTION FAULT (vector 13)"));
+    assert_eq!(lines.next(), Some("Error Code: selector 3 in the Gdt"));
+    assert!(lines.next().unwrap().starts_with("CR0: "));
+    assert!(text.contains("instruction_pointer: VirtAddr(\n        0x666630000000,\n    )"));
+}
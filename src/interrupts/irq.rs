@@ -0,0 +1,274 @@
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+use super::{apic, InterruptController, PICS};
+
+/// Number of legacy IRQ lines, 8 per PIC.
+pub const IRQ_COUNT: usize = 16;
+
+/// IRQ line the secondary PIC is cascaded to, it must stay unmasked while
+/// any of the secondary's IRQs is used.
+const CASCADE_IRQ: u8 = 2;
+
+/// Command ports of the primary and secondary PIC.
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+/// Command selecting the in-service register for the next read of the
+/// command port (OCW3).
+const PIC_READ_ISR: u8 = 0x0b;
+/// Non-specific end of interrupt command.
+const PIC_EOI: u8 = 0x20;
+
+/// The lowest priority IRQ of each PIC, a PIC raises it when the
+/// interrupt that caused it to signal the CPU vanished before the CPU
+/// acknowledged it.
+const SPURIOUS_IRQ_PRIMARY: u8 = 7;
+const SPURIOUS_IRQ_SECONDARY: u8 = 15;
+
+/// Number of spurious interrupts of the primary and secondary PIC and the
+/// local APIC.
+static SPURIOUS_PRIMARY: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_SECONDARY: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// Number of spurious interrupts received since boot, per source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpuriousIrqs {
+    /// Spurious IRQ 7 of the primary PIC
+    pub primary: u64,
+    /// Spurious IRQ 15 of the secondary PIC
+    pub secondary: u64,
+    /// Interrupts at the spurious vector of the local APIC
+    pub apic: u64,
+}
+
+/// Returns the number of spurious interrupts received since boot.
+pub fn spurious_irqs() -> SpuriousIrqs {
+    SpuriousIrqs {
+        primary: SPURIOUS_PRIMARY.load(Ordering::Relaxed),
+        secondary: SPURIOUS_SECONDARY.load(Ordering::Relaxed),
+        apic: SPURIOUS_APIC.load(Ordering::Relaxed),
+    }
+}
+
+/// Counts an interrupt at the spurious vector of the local APIC.
+pub(super) fn count_apic_spurious() {
+    SPURIOUS_APIC.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A function handling a hardware interrupt, it gets the IRQ number so one
+/// function can serve multiple lines. The end of interrupt is sent by the
+/// dispatcher after it returned.
+pub type IrqHandler = fn(irq: u8);
+
+/// Errors that can occur when registering or unregistering an IRQ handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ number is not below `IRQ_COUNT`
+    InvalidIrq,
+    /// Another handler is already registered for the IRQ
+    AlreadyRegistered,
+    /// No handler is registered for the IRQ
+    NotRegistered,
+    /// The interrupt controller can't mask or unmask the IRQ
+    Unroutable,
+}
+
+/// The registered handlers, stored as raw pointers so they can be read
+/// from the interrupt handlers without taking a lock.
+static HANDLERS: [AtomicPtr<()>; IRQ_COUNT] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; IRQ_COUNT];
+
+/// Defines one interrupt handler per IRQ line, they all forward to
+/// `dispatch` with their IRQ number.
+macro_rules! trampolines {
+    ($($name:ident = $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        /// The IDT handlers of the IRQ lines, indexed by IRQ number.
+        pub(super) const TRAMPOLINES: [HandlerFunc; IRQ_COUNT] = [$($name),*];
+    };
+}
+
+trampolines!(
+    irq_0 = 0, irq_1 = 1, irq_2 = 2, irq_3 = 3,
+    irq_4 = 4, irq_5 = 5, irq_6 = 6, irq_7 = 7,
+    irq_8 = 8, irq_9 = 9, irq_10 = 10, irq_11 = 11,
+    irq_12 = 12, irq_13 = 13, irq_14 = 14, irq_15 = 15,
+);
+
+/// Calls the handler registered for `irq` and acknowledges the interrupt
+/// at the active interrupt controller. Spurious interrupts of the PICs are
+/// only counted.
+fn dispatch(irq: u8) {
+    super::stats::record(super::PIC_1_OFFSET + irq);
+    if super::controller() == InterruptController::Pic && is_spurious(irq) {
+        acknowledge_spurious(irq);
+        return;
+    }
+
+    let handler = HANDLERS[irq as usize].load(Ordering::Acquire);
+    if !handler.is_null() {
+        let handler: IrqHandler = unsafe { core::mem::transmute(handler) };
+        handler(irq);
+    }
+    super::end_of_interrupt(irq);
+}
+
+/// Attaches `handler` to the IRQ line and unmasks it at the active
+/// interrupt controller.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let slot = HANDLERS.get(irq as usize).ok_or(IrqError::InvalidIrq)?;
+    slot.compare_exchange(
+        ptr::null_mut(),
+        handler as *mut (),
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    )
+    .map_err(|_| IrqError::AlreadyRegistered)?;
+
+    if let Err(err) = set_masked(irq, false) {
+        slot.store(ptr::null_mut(), Ordering::Release);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Masks the IRQ line at the active interrupt controller and detaches its
+/// handler, which is returned.
+pub fn unregister_irq(irq: u8) -> Result<IrqHandler, IrqError> {
+    let slot = HANDLERS.get(irq as usize).ok_or(IrqError::InvalidIrq)?;
+    if slot.load(Ordering::Acquire).is_null() {
+        return Err(IrqError::NotRegistered);
+    }
+
+    // Mask first, so the line can't fire between the two steps
+    set_masked(irq, true)?;
+    let handler = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+    Ok(unsafe { core::mem::transmute::<*mut (), IrqHandler>(handler) })
+}
+
+/// Returns `true` if a handler is registered for the IRQ.
+pub fn is_registered(irq: u8) -> bool {
+    HANDLERS
+        .get(irq as usize)
+        .is_some_and(|slot| !slot.load(Ordering::Acquire).is_null())
+}
+
+/// Masks or unmasks the IRQ line at the active interrupt controller.
+fn set_masked(irq: u8, masked: bool) -> Result<(), IrqError> {
+    match super::controller() {
+        InterruptController::Apic => {
+            apic::set_isa_irq_masked(irq, masked).map_err(|_| IrqError::Unroutable)
+        }
+        InterruptController::Pic => {
+            x86_64::instructions::interrupts::without_interrupts(|| {
+                let mut pics = PICS.lock();
+                let mut masks = unsafe { pics.read_masks() };
+                let (pic, bit) = ((irq / 8) as usize, irq % 8);
+                if masked {
+                    masks[pic] |= 1 << bit;
+                } else {
+                    masks[pic] &= !(1 << bit);
+                    if pic == 1 {
+                        masks[0] &= !(1 << CASCADE_IRQ);
+                    }
+                }
+                unsafe { pics.write_masks(masks[0], masks[1]) };
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Reads the in-service registers of both PICs, bit `n` is set while
+/// IRQ `n` is being handled.
+fn pic_in_service() -> u16 {
+    let mut primary = Port::<u8>::new(PIC_1_COMMAND);
+    let mut secondary = Port::<u8>::new(PIC_2_COMMAND);
+    unsafe {
+        primary.write(PIC_READ_ISR);
+        secondary.write(PIC_READ_ISR);
+        (secondary.read() as u16) << 8 | primary.read() as u16
+    }
+}
+
+/// Returns `true` if `irq` is the spurious IRQ of a PIC and the PIC
+/// doesn't have it in service, i.e. no device actually raised it.
+fn is_spurious(irq: u8) -> bool {
+    if irq != SPURIOUS_IRQ_PRIMARY && irq != SPURIOUS_IRQ_SECONDARY {
+        return false;
+    }
+    pic_in_service() & (1 << irq) == 0
+}
+
+/// Counts a spurious IRQ and acknowledges it where necessary. A spurious
+/// IRQ 7 must not be acknowledged, the primary PIC has nothing in service.
+/// For a spurious IRQ 15 the primary PIC did see a real interrupt on the
+/// cascade line, so it gets an end of interrupt, but the secondary doesn't.
+fn acknowledge_spurious(irq: u8) {
+    if irq == SPURIOUS_IRQ_SECONDARY {
+        SPURIOUS_SECONDARY.fetch_add(1, Ordering::Relaxed);
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+    } else {
+        SPURIOUS_PRIMARY.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Masks every IRQ line of the PICs except for the cascade, lines are
+/// unmasked when a handler is registered.
+pub(super) fn mask_all_pic_irqs() {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        PICS.lock().write_masks(!(1 << CASCADE_IRQ), 0xff);
+    });
+}
+
+#[test_case]
+fn test_register_unregister_irq() {
+    use core::sync::atomic::AtomicUsize;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn handler(irq: u8) {
+        assert_eq!(irq, 5);
+        CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    register_irq(5, handler).expect("registering the handler failed");
+    assert!(is_registered(5));
+    assert_eq!(register_irq(5, handler), Err(IrqError::AlreadyRegistered));
+    assert_eq!(register_irq(IRQ_COUNT as u8, handler), Err(IrqError::InvalidIrq));
+
+    // A software interrupt to the vector of IRQ 5 goes through the
+    // same trampoline as the hardware interrupt
+    unsafe { core::arch::asm!("int 37") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+    assert!(unregister_irq(5).is_ok());
+    assert!(!is_registered(5));
+    unsafe { core::arch::asm!("int 37") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(unregister_irq(5).err(), Some(IrqError::NotRegistered));
+}
+
+#[test_case]
+fn test_spurious_irq_detection() {
+    // No interrupt is in service while the test runs, so the lowest
+    // priority IRQs of both PICs would be spurious
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        assert!(is_spurious(SPURIOUS_IRQ_PRIMARY));
+        assert!(is_spurious(SPURIOUS_IRQ_SECONDARY));
+        assert!(!is_spurious(1));
+    });
+
+    let before = spurious_irqs();
+    acknowledge_spurious(SPURIOUS_IRQ_PRIMARY);
+    acknowledge_spurious(SPURIOUS_IRQ_SECONDARY);
+    let after = spurious_irqs();
+    assert_eq!(after.primary, before.primary + 1);
+    assert_eq!(after.secondary, before.secondary + 1);
+}
@@ -0,0 +1,102 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::serial_println;
+
+/// Number of interrupts received per vector since boot.
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Names of the exception vectors, reserved vectors are left empty.
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE ERROR",
+    "DEBUG",
+    "NON-MASKABLE INTERRUPT",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "",
+    "X87 FLOATING POINT",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING POINT",
+    "VIRTUALIZATION",
+    "", "", "", "", "", "", "", "",
+    "VMM COMMUNICATION EXCEPTION",
+    "SECURITY EXCEPTION",
+    "",
+];
+
+/// Names of the legacy IRQ lines, the devices usually connected to them.
+const IRQ_NAMES: [&str; super::IRQ_COUNT] = [
+    "TIMER",
+    "KEYBOARD",
+    "CASCADE",
+    "SERIAL PORT 2",
+    "SERIAL PORT 1",
+    "PARALLEL PORT 2/3",
+    "FLOPPY DISK",
+    "PARALLEL PORT 1",
+    "REAL TIME CLOCK",
+    "ACPI",
+    "IRQ 10",
+    "IRQ 11",
+    "MOUSE",
+    "CO-PROCESSOR",
+    "PRIMARY ATA",
+    "SECONDARY ATA",
+];
+
+/// Counts an interrupt at `vector`, called first thing by every handler.
+pub(super) fn record(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of interrupts received at `vector` since boot.
+pub fn interrupt_count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Returns a name describing what raises the interrupt vector.
+pub fn vector_name(vector: u8) -> &'static str {
+    let irq = vector.wrapping_sub(super::PIC_1_OFFSET) as usize;
+    match vector as usize {
+        vector if vector < EXCEPTION_NAMES.len() && !EXCEPTION_NAMES[vector].is_empty() => {
+            EXCEPTION_NAMES[vector]
+        }
+        _ if irq < IRQ_NAMES.len() => IRQ_NAMES[irq],
+        _ if vector == super::apic::SPURIOUS_VECTOR => "APIC SPURIOUS",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Prints a table of every vector that received interrupts since boot to
+/// the serial port.
+pub fn irqstat() {
+    serial_println!("{:>6}  {:<28} {:>12}", "vector", "name", "count");
+    for vector in 0..=u8::MAX {
+        let count = interrupt_count(vector);
+        if count > 0 {
+            serial_println!("{:>6}  {:<28} {:>12}", vector, vector_name(vector), count);
+        }
+    }
+}
+
+#[test_case]
+fn test_interrupt_counters() {
+    let breakpoints = interrupt_count(3);
+    x86_64::instructions::interrupts::int3();
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(interrupt_count(3), breakpoints + 2);
+
+    assert_eq!(vector_name(3), "BREAKPOINT");
+    assert_eq!(vector_name(super::InterruptIndex::Keyboard as u8), "KEYBOARD");
+    assert_eq!(vector_name(super::apic::SPURIOUS_VECTOR), "APIC SPURIOUS");
+    assert_eq!(vector_name(9), "UNKNOWN");
+}
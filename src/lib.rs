@@ -8,13 +8,27 @@
 // to something different than main
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
 
 pub mod vga_buffer;
 pub mod serial;
 pub mod interrupts;
 pub mod gdt;
+pub mod memory;
+pub mod allocator;
+pub mod cpu;
+pub mod acpi;
+pub mod time;
 
 use core::panic::PanicInfo;
+use bootloader::BootInfo;
+#[cfg(test)]
+use bootloader::entry_point;
+use x86_64::structures::paging::OffsetPageTable;
+use interrupts::CrashReport;
+use memory::BootInfoFrameAllocator;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 // Represented as u32, since the port size is four bytes
@@ -24,16 +38,57 @@ pub enum QemuExitCode {
     Failed = 0x11,
 }
 
-/// Initialize the GDT and IDT
-pub fn init() {
+/// Settings for `init` that can be chosen at boot.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Controller for the hardware interrupts, the PIC is used if the
+    /// APIC is requested but not available
+    pub interrupt_controller: interrupts::InterruptController,
+    /// Device raising the timer interrupt, the PIT is used if the HPET is
+    /// requested but not available
+    pub timer_source: time::TimerSource,
+    /// Rate of the timer interrupt in Hz
+    pub timer_frequency: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            interrupt_controller: interrupts::InterruptController::Apic,
+            timer_source: time::TimerSource::Pit,
+            timer_frequency: time::DEFAULT_TIMER_FREQUENCY,
+        }
+    }
+}
+
+/// Initialize the GDT and IDT, enable the CPU's protections against user
+/// space, program the page attribute table, set up the interrupt
+/// controller, start the timer and calibrate the TSC. The interrupt stacks
+/// must have been allocated with `gdt::init_stacks` before. The APIC
+/// registers are mapped with `memory::mmio`, so using the APIC requires the
+/// page table and frame allocator to be handed over to `memory` first, the
+/// same goes for the HPET.
+pub fn init(config: Config) {
     gdt::init();
+    cpu::harden();
+    memory::mmio::init_pat();
     interrupts::init_idt();
-    // Initialize PICS
-    unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_controller(config.interrupt_controller);
+    time::init(config.timer_source, config.timer_frequency);
+    if time::calibrate_tsc().is_none() {
+        println!("TSC calibration failed, timestamps use the timer ticks");
+    }
     // Enable interupts
     x86_64::instructions::interrupts::enable();
 }
 
+#[alloc_error_handler]
+/// This function is called when a heap allocation fails.
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    serial_println!("Allocation error: {:?}", layout);
+    panic!("allocation error: {:?}", layout)
+}
+
 /// Halt the CPU until the next interrupt arrives
 pub fn hlt_loop() -> ! {
     loop {
@@ -53,14 +108,14 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 }
 
 pub trait Testable {
-    fn run(&self) -> ();
+    fn run(&self);
 }
 
 impl<T> Testable for T
 where
     T: Fn(),
 {
-    fn run(&self) -> () {
+    fn run(&self) {
         // Print the function name
         serial_print!("{}...\t", core::any::type_name::<T>());
         self();
@@ -84,6 +139,74 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+/// The exception an integration test triggers and what the kernel has to
+/// report about it.
+#[derive(Debug)]
+pub struct ExpectedCrash {
+    pub vector: u8,
+    pub name: &'static str,
+    /// Whether the exception pushes an error code
+    pub error_code: bool,
+    /// Bytes of the faulting instruction, the instruction pointer of the
+    /// report must point at them. `None` if the test doesn't know it.
+    pub instruction: Option<&'static [u8]>,
+    /// The stack whose guard page was hit, if any
+    pub overflowed_stack: Option<&'static str>,
+}
+
+/// The crash the running integration test expects.
+static EXPECTED_CRASH: spin::Once<&'static ExpectedCrash> = spin::Once::new();
+
+/// Sets up the interrupt stacks, the GDT and the IDT for an integration
+/// test that triggers an exception, the test passes if the kernel reports
+/// `expected`. Returns the page table and frame allocator, e.g. for
+/// allocating further stacks.
+pub fn init_crash_test(
+    boot_info: &'static BootInfo,
+    expected: &'static ExpectedCrash,
+) -> (OffsetPageTable<'static>, BootInfoFrameAllocator) {
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    gdt::init_stacks(Default::default(), &mut mapper, &mut frame_allocator)
+        .expect("interrupt stack allocation failed");
+
+    gdt::init();
+    interrupts::init_idt();
+    EXPECTED_CRASH.call_once(|| expected);
+    interrupts::set_crash_handler(check_crash_report);
+
+    (mapper, frame_allocator)
+}
+
+/// Crash handler of `init_crash_test`, compares the report with the
+/// expected one and exits QEMU.
+fn check_crash_report(report: &CrashReport) -> ! {
+    let expected = EXPECTED_CRASH.get().expect("no crash expected");
+    let instruction_matches = expected.instruction.is_none_or(|instruction| {
+        let ip: *const u8 = report.stack_frame.instruction_pointer.as_ptr();
+        unsafe { core::slice::from_raw_parts(ip, instruction.len()) == instruction }
+    });
+    if report.vector == expected.vector
+        && report.name == expected.name
+        && report.error_code.is_some() == expected.error_code
+        && report.overflowed_stack == expected.overflowed_stack
+        && instruction_matches
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected crash report\n{}\n", report);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
 #[cfg(test)]
 #[panic_handler]
 /// This function is called on panic when in test mode.
@@ -92,9 +215,15 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    init();
+entry_point!(test_kernel_main);
+
+#[cfg(test)]
+/// Entry point for `cargo test`
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    // The interrupt stacks are allocated from the page allocator,
+    // so the memory management has to be set up first
+    memory::init_test(boot_info);
+    init(Config::default());
     test_main();
     hlt_loop();
 }
\ No newline at end of file
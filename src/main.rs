@@ -9,6 +9,7 @@
 #![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
 
 mod vga_buffer;
 mod serial;
@@ -28,37 +29,91 @@ fn panic(info: &PanicInfo) -> ! {
     os::test_panic_handler(info);
 }
 
-// Disable name mangling to ensure that Rust really
-// outputs a function with the name _start, without
-// the attribute the compiler would generate some 
-// random name.
-#[no_mangle]
-// Mark as `extern "C"` to tell the compiler that 
-// it should use the C calling convention for this
-// function
-pub extern "C" fn _start() -> ! {
-    // This function is the entry point, since the
-    // linker looks for a function named `_start` 
-    // by default.
+// Define the entry point, the macro provides a type-checked way
+// to define a Rust function as the entry point (`_start`) which
+// is called by the bootloader with a reference to the boot
+// information.
+entry_point!(kernel_main);
+
+/// Size of the kernel stack in pages
+const KERNEL_STACK_PAGES: u64 = 32;
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    use os::allocator;
+    use os::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+    use x86_64::registers::control::Cr3;
 
     print!("Starting...\t");
 
-    os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    // Remap the kernel so that no page is both writable and executable
+    memory::protect::protect_kernel(&mut mapper).expect("protecting the kernel failed");
+    // Create a frame allocator from the memory map passed by
+    // the bootloader
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    // Reference counts of the frames shared by copy-on-write pages
+    memory::cow::init(&mut frame_allocator);
+    // Page tables for the whole kernel part, shared by all address spaces
+    memory::address_space::init(&mut frame_allocator)
+        .expect("creating the kernel page tables failed");
 
-    println!("[done]");
+    // The interrupt stacks are allocated from the page allocator, so
+    // the memory management has to be set up before the GDT
+    os::gdt::init_stacks(Default::default(), &mut mapper, &mut frame_allocator)
+        .expect("interrupt stack allocation failed");
 
-    use x86_64::registers::control::Cr3;
+    // Hand the page table and the frame allocator over to the kernel
+    // so they can be used by the slab caches, the page fault handler
+    // and the MMIO mappings of the interrupt controller
+    memory::init_mapper(mapper);
+    memory::init_frame_allocator(frame_allocator);
+
+    os::init(os::Config::default());
+
+    println!("[done]");
+    println!("CPU protections: {}", os::cpu::Protections::enabled());
+    println!("Interrupt controller: {:?}", os::interrupts::controller());
+    println!("Timer: {:?} at {} Hz", os::time::timer_source(), os::time::timer_frequency());
+    if let Some(frequency) = os::time::tsc_frequency() {
+        let invariant = if os::time::tsc_is_invariant() { "invariant" } else { "variant" };
+        println!("TSC: {} MHz ({})", frequency / 1_000_000, invariant);
+    }
 
     let (level_4_page_table, _) = Cr3::read();
     println!("Level 4 page table at: {:?}", level_4_page_table.start_address());
 
+    // The heap pages are mapped on their first access
+    allocator::init_heap().expect("heap initialization failed");
+
+    // Move the kernel to its own stack with a guard page, the pages
+    // below its top are only mapped when it grows into them
+    let kernel_stack = memory::with_mapper(|mapper| {
+        memory::with_frame_allocator(|frame_allocator| {
+            let pages = KERNEL_STACK_PAGES;
+            memory::stack::allocate_lazy_stack("kernel stack", pages, mapper, frame_allocator)
+        })
+    })
+    .expect("kernel stack allocation failed");
+
+    println!("{}", memory::stats());
+
+    // Nothing on the bootloader's stack is needed anymore
+    unsafe { memory::stack::switch_to(&kernel_stack, kernel_main_on_stack) }
+}
+
+/// Continuation of `kernel_main`, running on the kernel's own stack.
+extern "C" fn kernel_main_on_stack() -> ! {
     #[cfg(test)]
     // (This function is generated by the test framework (it 
     // calls the test_runner), normally the test framework 
     // generates a function called main)
     test_main();
 
-    println!("Yay no crash!");
+    println!("Yay no crash! (uptime {:?})", os::time::uptime());
 
     os::hlt_loop();
 }
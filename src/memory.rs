@@ -0,0 +1,652 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+    PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError,
+};
+use spin::{Mutex, Once};
+use core::fmt;
+
+pub mod buddy;
+pub mod slab;
+pub mod stack;
+pub mod lazy;
+pub mod protect;
+pub mod user;
+pub mod cow;
+pub mod address_space;
+pub mod vmalloc;
+pub mod mmio;
+
+use buddy::BuddyFrameAllocator;
+
+/// Virtual address at which the bootloader mapped the complete physical
+/// memory, set by `init`.
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// The kernel's physical frame allocator, set by `init_frame_allocator`.
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+/// The kernel's page table, set by `init_mapper`.
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// Initialize a new OffsetPageTable.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Returns the virtual address at which the complete physical memory is
+/// mapped.
+///
+/// Panics if `init` was not called yet.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET.get().expect("memory::init was not called")
+}
+
+/// Hands the frame allocator over to the kernel, afterwards it can be used
+/// through `with_frame_allocator` by code that has no other way to reach
+/// it (slab caches, interrupt handlers).
+pub fn init_frame_allocator(frame_allocator: BuddyFrameAllocator) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+/// Runs `f` with the kernel's frame allocator, interrupts are disabled
+/// while the allocator is locked.
+///
+/// Panics if `init_frame_allocator` was not called yet.
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BuddyFrameAllocator) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        f(frame_allocator.as_mut().expect("frame allocator not initialized"))
+    })
+}
+
+/// Hands the page table returned by `init` over to the kernel, afterwards
+/// it can be used through `with_mapper`.
+pub fn init_mapper(mapper: OffsetPageTable<'static>) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *MAPPER.lock() = Some(mapper);
+    });
+}
+
+/// Runs `f` with the kernel's page table, interrupts are disabled while
+/// the page table is locked.
+///
+/// When both are needed, the page table has to be locked before the
+/// frame allocator.
+///
+/// Panics if `init_mapper` was not called yet.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        f(mapper.as_mut().expect("page table not initialized"))
+    })
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// # Safety
+///
+/// This function is unsafe for the same reasons as `init`.
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    // Read the physical frame of the active level 4 table from
+    // the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    // The bootloader maps the complete physical memory at the
+    // offset, so the table is reachable at offset + phys
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped.
+pub fn translate_addr(mapper: &OffsetPageTable, addr: VirtAddr) -> Option<PhysAddr> {
+    mapper.translate_addr(addr)
+}
+
+/// Maps the given page to the given frame with the passed flags and flushes
+/// the page from the TLB. Missing page tables are allocated from
+/// `frame_allocator`.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the frame
+/// is not already in use, mapping it twice could cause memory corruption.
+pub unsafe fn map_page(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    Ok(())
+}
+
+/// Removes the mapping of the given page, flushes it from the TLB and returns
+/// the frame it was mapped to.
+pub fn unmap_page(page: Page, mapper: &mut OffsetPageTable) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    Ok(frame)
+}
+
+/// Replaces the flags of the given (already mapped) page and flushes it from
+/// the TLB.
+///
+/// # Safety
+///
+/// This function is unsafe because changing the flags can break memory
+/// safety, e.g. by making memory that is referenced elsewhere non-present.
+pub unsafe fn update_flags(
+    page: Page,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), FlagUpdateError> {
+    mapper.update_flags(page, flags)?.flush();
+    Ok(())
+}
+
+/// Errors that can occur when mapping huge pages or ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageError {
+    /// The CPU doesn't support pages of the requested size
+    Unsupported,
+    /// An address or size is not aligned to the page size
+    NotAligned,
+    /// Allocating a frame for a page table failed
+    FrameAllocationFailed,
+    /// A parent entry of the page is already mapped as a huge page
+    ParentEntryHugePage,
+    /// The page at the given address is already mapped
+    PageAlreadyMapped(VirtAddr),
+    /// The range to unmap covers only part of the huge page at the given
+    /// address
+    PartialHugePage(VirtAddr),
+    /// Unmapping the page at the given address failed
+    UnmapFailed(VirtAddr),
+}
+
+impl HugePageError {
+    /// Converts the error of mapping the page at `addr`.
+    fn from_map_to_error<S: PageSize>(error: MapToError<S>, addr: VirtAddr) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => HugePageError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => HugePageError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(_) => HugePageError::PageAlreadyMapped(addr),
+        }
+    }
+}
+
+/// Returns `true` if the CPU supports pages of size `S`.
+pub fn page_size_supported<S: PageSize>() -> bool {
+    match S::SIZE {
+        Size4KiB::SIZE | Size2MiB::SIZE => true,
+        Size1GiB::SIZE => crate::cpu::has_1gib_pages(),
+        _ => false,
+    }
+}
+
+/// Maps the given 2 MiB or 1 GiB page to the given frame with the passed flags
+/// and flushes the page from the TLB.
+///
+/// # Safety
+///
+/// This function is unsafe for the same reasons as `map_page`.
+pub unsafe fn map_huge_page<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), HugePageError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    if !page_size_supported::<S>() {
+        return Err(HugePageError::Unsupported);
+    }
+    // The mapper sets the HUGE_PAGE flag for 2 MiB and 1 GiB pages
+    mapper
+        .map_to(page, frame, flags, frame_allocator)
+        .map_err(|error| HugePageError::from_map_to_error(error, page.start_address()))?
+        .flush();
+    Ok(())
+}
+
+/// Removes the mapping of the given 2 MiB or 1 GiB page, flushes it from the
+/// TLB and returns the frame it was mapped to.
+pub fn unmap_huge_page<S: PageSize>(
+    page: Page<S>,
+    mapper: &mut OffsetPageTable,
+) -> Result<PhysFrame<S>, UnmapError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    Ok(frame)
+}
+
+/// Maps `size` bytes of physical memory starting at `phys` to `virt`, using
+/// the largest pages the alignment of the addresses and the CPU allow for.
+///
+/// # Safety
+///
+/// This function is unsafe for the same reasons as `map_page`.
+pub unsafe fn map_range(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), HugePageError> {
+    let page_aligned = virt.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE);
+    if !page_aligned || !size.is_multiple_of(Size4KiB::SIZE) {
+        return Err(HugePageError::NotAligned);
+    }
+
+    let use_1gib_pages = page_size_supported::<Size1GiB>();
+    let mut offset = 0;
+    while offset < size {
+        let (virt, phys, remaining) = (virt + offset, phys + offset, size - offset);
+        let fits = |page_size: u64| {
+            virt.is_aligned(page_size) && phys.is_aligned(page_size) && remaining >= page_size
+        };
+
+        offset += if use_1gib_pages && fits(Size1GiB::SIZE) {
+            let page = Page::<Size1GiB>::containing_address(virt);
+            let frame = PhysFrame::<Size1GiB>::containing_address(phys);
+            map_huge_page(page, frame, flags, mapper, frame_allocator)?;
+            Size1GiB::SIZE
+        } else if fits(Size2MiB::SIZE) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+            map_huge_page(page, frame, flags, mapper, frame_allocator)?;
+            Size2MiB::SIZE
+        } else {
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+            map_page(page, frame, flags, mapper, frame_allocator)
+                .map_err(|error| HugePageError::from_map_to_error(error, virt))?;
+            Size4KiB::SIZE
+        };
+    }
+
+    Ok(())
+}
+
+/// Removes all mappings in the `size` bytes starting at `virt`, no matter
+/// which page size they use. Unmapped parts of the range are skipped.
+///
+/// Fails with `PartialHugePage` if the range covers only part of a huge
+/// page, the huge page and everything after it stay mapped then. The
+/// mappings before it are removed already.
+pub fn unmap_range(
+    virt: VirtAddr,
+    size: u64,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), HugePageError> {
+    let end = virt + size;
+    let mut addr = virt;
+    while addr < end {
+        let page_size = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), .. } => Size1GiB::SIZE,
+            TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), .. } => Size2MiB::SIZE,
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. } => Size4KiB::SIZE,
+            _ => {
+                addr = addr.align_down(Size4KiB::SIZE) + Size4KiB::SIZE;
+                continue;
+            }
+        };
+        let start = addr.align_down(page_size);
+        if page_size != Size4KiB::SIZE && (start < virt || start + page_size > end) {
+            return Err(HugePageError::PartialHugePage(start));
+        }
+
+        // Unmap whichever page size the address is mapped with
+        let result = match page_size {
+            Size1GiB::SIZE => {
+                unmap_huge_page(Page::<Size1GiB>::containing_address(start), mapper).map(|_| ())
+            }
+            Size2MiB::SIZE => {
+                unmap_huge_page(Page::<Size2MiB>::containing_address(start), mapper).map(|_| ())
+            }
+            _ => unmap_page(Page::containing_address(start), mapper).map(|_| ()),
+        };
+        result.map_err(|_| HugePageError::UnmapFailed(start))?;
+        addr = start + page_size;
+    }
+    Ok(())
+}
+
+/// A snapshot of the kernel's memory usage, returned by `stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// Physical memory described by the memory map in bytes
+    pub total_memory: u64,
+    /// Physical memory managed by the frame allocator in bytes
+    pub usable_memory: u64,
+    /// Physical memory that is reserved or was in use at boot in bytes
+    pub reserved_memory: u64,
+    /// Frames currently allocated from the frame allocator
+    pub used_frames: usize,
+    /// Frames currently free in the frame allocator
+    pub free_frames: usize,
+    /// Frames used by the active page tables, including the level 4 table
+    pub page_table_frames: usize,
+    /// Usage of the kernel heap
+    pub heap: crate::allocator::HeapStats,
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "MemTotal:      {:>10} KiB", self.total_memory / 1024)?;
+        writeln!(f, "MemUsable:     {:>10} KiB", self.usable_memory / 1024)?;
+        writeln!(f, "MemReserved:   {:>10} KiB", self.reserved_memory / 1024)?;
+        writeln!(f, "FramesUsed:    {:>10}", self.used_frames)?;
+        writeln!(f, "FramesFree:    {:>10}", self.free_frames)?;
+        writeln!(f, "PageTables:    {:>10} KiB", self.page_table_frames * 4)?;
+        writeln!(f, "HeapSize:      {:>10} KiB", self.heap.size / 1024)?;
+        writeln!(f, "HeapUsed:      {:>10} B", self.heap.used)?;
+        write!(f, "HeapFree:      {:>10} B", self.heap.free)
+    }
+}
+
+/// Returns the current memory usage of the kernel.
+///
+/// Panics if the page table and the frame allocator were not handed over
+/// with `init_mapper` and `init_frame_allocator` yet.
+pub fn stats() -> MemoryStats {
+    let page_table_frames = with_mapper(|mapper| {
+        count_page_tables(mapper.level_4_table(), 4, physical_memory_offset())
+    });
+    with_frame_allocator(|frame_allocator| {
+        let usable_frames = frame_allocator.total_frames() as u64;
+        let reserved_frames = frame_allocator.reserved_frames() as u64;
+        MemoryStats {
+            total_memory: (usable_frames + reserved_frames) * Size4KiB::SIZE,
+            usable_memory: usable_frames * Size4KiB::SIZE,
+            reserved_memory: reserved_frames * Size4KiB::SIZE,
+            used_frames: frame_allocator.used_frames(),
+            free_frames: frame_allocator.free_frames(),
+            page_table_frames,
+            heap: crate::allocator::heap_stats(),
+        }
+    })
+}
+
+/// Prints the memory usage to the serial port, so it can be inspected
+/// while the kernel is running.
+pub fn meminfo() {
+    crate::serial_println!("{}", stats());
+}
+
+/// Counts the given page table of the given level and all tables below it.
+fn count_page_tables(table: &PageTable, level: u8, physical_memory_offset: VirtAddr) -> usize {
+    if level == 1 {
+        return 1;
+    }
+    let children: usize = table
+        .iter()
+        .filter(|entry| {
+            let flags = entry.flags();
+            // Huge pages map memory directly instead of pointing to a
+            // table of the next level
+            flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+        })
+        .map(|entry| {
+            let virt = physical_memory_offset + entry.addr().as_u64();
+            let next_table = unsafe { &*virt.as_ptr::<PageTable>() };
+            count_page_tables(next_table, level - 1, physical_memory_offset)
+        })
+        .sum();
+    1 + children
+}
+
+/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    /// Number of the next frame that should be returned
+    next: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+        }
+    }
+
+    /// Returns an iterator over the usable frames specified in the memory map.
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        // Get usable regions from the memory map, every other region
+        // is either in use (kernel, page tables, boot info) or
+        // reserved by the firmware.
+        let regions = self.memory_map.iter();
+        let usable_regions = regions
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        // Map each region to its address range
+        let addr_ranges = usable_regions
+            .map(|r| r.range.start_addr()..r.range.end_addr());
+        // Transform to an iterator of frame start addresses, the
+        // bootloader page aligns all usable memory areas so we can
+        // step by the page size.
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // Create `PhysFrame` types from the start addresses
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // Since the iterator is recreated every time we skip the
+        // frames that were already handed out.
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn init_test(boot_info: &'static bootloader::BootInfo) {
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    init_mapper(unsafe { init(physical_memory_offset) });
+    with_mapper(|mapper| protect::protect_kernel(mapper).expect("protecting the kernel failed"));
+    init_frame_allocator(unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    });
+    with_frame_allocator(cow::init);
+    with_frame_allocator(address_space::init).expect("creating the kernel page tables failed");
+    with_mapper(|mapper| {
+        with_frame_allocator(|frame_allocator| {
+            crate::gdt::init_stacks(Default::default(), mapper, frame_allocator)
+                .expect("interrupt stack allocation failed");
+        })
+    });
+}
+
+#[test_case]
+fn test_translate_identity_mapped() {
+    // The bootloader identity maps the VGA buffer
+    let addr = VirtAddr::new(0xb8000);
+    let phys = with_mapper(|mapper| translate_addr(mapper, addr));
+    assert_eq!(phys, Some(PhysAddr::new(0xb8000)));
+}
+
+#[test_case]
+fn test_translate_physical_memory_offset() {
+    let offset = physical_memory_offset();
+    // Physical address 0 is mapped at the start of the physical
+    // memory mapping
+    with_mapper(|mapper| {
+        assert_eq!(translate_addr(mapper, offset), Some(PhysAddr::new(0)));
+        assert_eq!(translate_addr(mapper, offset + 0x1234u64), Some(PhysAddr::new(0x1234)));
+    });
+}
+
+#[test_case]
+fn test_map_translate_unmap() {
+    with_mapper(|mapper| {
+        let page: Page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let frame = with_frame_allocator(|frame_allocator| {
+            let frame = frame_allocator.allocate_frame().expect("no frames left");
+            unsafe {
+                map_page(page, frame, flags, mapper, frame_allocator).expect("map_page failed");
+            }
+            frame
+        });
+        assert_eq!(
+            translate_addr(mapper, page.start_address() + 0x42u64),
+            Some(frame.start_address() + 0x42u64)
+        );
+
+        // Write through the new mapping and read it back through the
+        // physical memory mapping
+        let ptr: *mut u64 = page.start_address().as_mut_ptr();
+        unsafe { ptr.write_volatile(0xf021_f077_f065_f04e) };
+        let phys_ptr: *const u64 = (physical_memory_offset() + frame.start_address().as_u64()).as_ptr();
+        assert_eq!(unsafe { phys_ptr.read_volatile() }, 0xf021_f077_f065_f04e);
+
+        unsafe {
+            update_flags(page, PageTableFlags::PRESENT, mapper).expect("update_flags failed");
+        }
+        assert_eq!(mapper.translate_page(page).ok(), Some(frame));
+
+        assert_eq!(unmap_page(page, mapper).ok(), Some(frame));
+        assert_eq!(translate_addr(mapper, page.start_address()), None);
+        with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate(frame, 0) });
+    });
+}
+
+#[test_case]
+fn test_map_range_uses_2mib_pages() {
+    // A 4 MiB block is aligned to 4 MiB, so the first 2 MiB of the
+    // range can use a single 2 MiB page
+    let block = with_frame_allocator(|frame_allocator| frame_allocator.allocate_contiguous(10))
+        .expect("no 4 MiB block left");
+    let virt = VirtAddr::new(0x_7777_0000_0000);
+    let size = Size2MiB::SIZE + Size4KiB::SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    with_mapper(|mapper| {
+        with_frame_allocator(|frame_allocator| unsafe {
+            map_range(virt, block.start_address(), size, flags, mapper, frame_allocator)
+                .expect("map_range failed");
+        });
+
+        // Walk through the 2 MiB entry
+        match mapper.translate(virt + 0x1234u64) {
+            TranslateResult::Mapped { frame: MappedFrame::Size2MiB(frame), offset, .. } => {
+                assert_eq!(frame.start_address(), block.start_address());
+                assert_eq!(offset, 0x1234);
+            }
+            result => panic!("expected a 2 MiB mapping, got {:?}", result),
+        }
+        // The rest of the range uses a 4 KiB page
+        match mapper.translate(virt + Size2MiB::SIZE + 8u64) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), offset, .. } => {
+                assert_eq!(frame.start_address(), block.start_address() + Size2MiB::SIZE);
+                assert_eq!(offset, 8);
+            }
+            result => panic!("expected a 4 KiB mapping, got {:?}", result),
+        }
+        assert_eq!(
+            translate_addr(mapper, virt + 0x1f_f000u64),
+            Some(block.start_address() + 0x1f_f000u64)
+        );
+
+        // Half of the 2 MiB page can't be unmapped
+        assert_eq!(
+            unmap_range(virt + Size2MiB::SIZE / 2, Size2MiB::SIZE / 2, mapper),
+            Err(HugePageError::PartialHugePage(virt))
+        );
+        assert_eq!(translate_addr(mapper, virt), Some(block.start_address()));
+
+        unmap_range(virt, size, mapper).expect("unmap_range failed");
+        assert_eq!(translate_addr(mapper, virt), None);
+        assert_eq!(translate_addr(mapper, virt + Size2MiB::SIZE), None);
+    });
+
+    with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate(block, 10) });
+}
+
+#[test_case]
+fn test_map_1gib_page() {
+    let page = Page::<Size1GiB>::containing_address(VirtAddr::new(0x_7780_0000_0000));
+    // Map the first GiB of physical memory a second time, read only
+    let frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(0));
+    let flags = PageTableFlags::PRESENT;
+
+    with_mapper(|mapper| {
+        let result = with_frame_allocator(|frame_allocator| unsafe {
+            map_huge_page(page, frame, flags, mapper, frame_allocator)
+        });
+        if !page_size_supported::<Size1GiB>() {
+            assert_eq!(result, Err(HugePageError::Unsupported));
+            return;
+        }
+        result.expect("map_huge_page failed");
+
+        // Walk through the 1 GiB entry
+        let addr = page.start_address() + 0x0123_4567u64;
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { frame: MappedFrame::Size1GiB(mapped), offset, .. } => {
+                assert_eq!(mapped, frame);
+                assert_eq!(offset, 0x0123_4567);
+            }
+            result => panic!("expected a 1 GiB mapping, got {:?}", result),
+        }
+        assert_eq!(translate_addr(mapper, addr), Some(PhysAddr::new(0x0123_4567)));
+
+        assert_eq!(unmap_huge_page(page, mapper).ok(), Some(frame));
+        assert_eq!(translate_addr(mapper, addr), None);
+    });
+}
+
+#[test_case]
+fn test_stats() {
+    let before = stats();
+    assert_eq!(before.total_memory, before.usable_memory + before.reserved_memory);
+    assert!(before.usable_memory > 0);
+    // At least one table of each level is needed to map anything
+    assert!(before.page_table_frames >= 4);
+
+    let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+        .expect("no frames left");
+    let after = stats();
+    assert_eq!(after.used_frames, before.used_frames + 1);
+    assert_eq!(after.free_frames, before.free_frames - 1);
+    with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate(frame, 0) });
+    assert_eq!(stats().used_frames, before.used_frames);
+}
@@ -0,0 +1,428 @@
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    FrameAllocator, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    page_table::PageTableEntry,
+};
+use super::buddy::BuddyFrameAllocator;
+use super::cow::{self, CowError};
+
+/// Start of the user part of every address space, the first address of
+/// level 4 entry 64.
+pub const USER_START: u64 = 0x_2000_0000_0000;
+/// End of the user part of every address space, the first address of
+/// level 4 entry 128.
+pub const USER_END: u64 = 0x_4000_0000_0000;
+
+/// Level 4 entries covering the user part.
+const USER_ENTRIES: core::ops::Range<usize> =
+    (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Errors that can occur when creating or cloning an address space.
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// No frame for a page table was left
+    FrameAllocationFailed,
+    /// The user part contains a huge page, which can't be shared
+    HugePage(VirtAddr),
+    /// Sharing a user page with the new address space failed
+    Cow(CowError),
+}
+
+/// A set of page tables, the foundation for isolating processes.
+///
+/// The user part (`USER_START..USER_END`) is private to each address
+/// space, everything else belongs to the kernel. The kernel part is shared
+/// by pointing the level 4 entries of all address spaces to the same level
+/// 3 tables. `init` creates all of them at boot, so kernel mappings are
+/// visible everywhere, no matter when they are created.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+/// Creates an empty level 3 table below every unused kernel entry of the
+/// active level 4 table, which new address spaces copy their kernel part
+/// from. Must be called once, before the first address space is created.
+pub fn init(frame_allocator: &mut BuddyFrameAllocator) -> Result<(), AddressSpaceError> {
+    let kernel_table = unsafe { &mut *table_ptr(Cr3::read().0) };
+    for (index, entry) in kernel_table.iter_mut().enumerate() {
+        if USER_ENTRIES.contains(&index) || !entry.is_unused() {
+            continue;
+        }
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        unsafe { (*table_ptr(frame)).zero() };
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    Ok(())
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user part.
+    pub fn new(frame_allocator: &mut BuddyFrameAllocator) -> Result<Self, AddressSpaceError> {
+        let level_4_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        let level_4_table = unsafe { &mut *table_ptr(level_4_frame) };
+        level_4_table.zero();
+
+        // Share the kernel's level 3 tables
+        let kernel_table = unsafe { &*table_ptr(Cr3::read().0) };
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if !USER_ENTRIES.contains(&index) {
+                level_4_table[index] = entry.clone();
+            }
+        }
+
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    /// Returns the address space that is currently active.
+    ///
+    /// The returned value does not own the page tables, it must not be
+    /// destroyed while anything else uses them.
+    pub fn current() -> Self {
+        AddressSpace {
+            level_4_frame: Cr3::read().0,
+        }
+    }
+
+    /// Frame of the level 4 table, the value loaded into CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns `true` if this address space is the active one.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Returns a mapper for the page tables of this address space.
+    ///
+    /// While the address space is active, `memory::with_mapper` should be
+    /// used instead, the page fault handler modifies the tables through it.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let level_4_table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        unsafe { OffsetPageTable::new(level_4_table, super::physical_memory_offset()) }
+    }
+
+    /// Creates a new address space with the same user mappings.
+    ///
+    /// The user pages are not copied but shared as copy-on-write pages, the
+    /// first write to a page copies it in the address space that wrote it.
+    pub fn clone_user(
+        &mut self,
+        frame_allocator: &mut BuddyFrameAllocator,
+    ) -> Result<AddressSpace, AddressSpaceError> {
+        let mut clone = AddressSpace::new(frame_allocator)?;
+        let mut clone_mapper = clone.mapper();
+
+        let result = for_each_user_page(&mut self.mapper(), &mut |mapper, page| {
+            let (frame, flags) = cow::mark_cow(page, mapper).map_err(AddressSpaceError::Cow)?;
+            unsafe {
+                cow::map_cow(page, frame, flags, &mut clone_mapper, frame_allocator)
+                    .map_err(AddressSpaceError::Cow)
+            }
+        });
+        if let Err(err) = result {
+            // Drops the references of the pages shared so far, the pages
+            // of this address space stay copy-on-write and are taken
+            // over again by their next write
+            unsafe { clone.destroy(frame_allocator) };
+            return Err(err);
+        }
+
+        Ok(clone)
+    }
+
+    /// Switches to this address space by loading its level 4 table into
+    /// CR3, `memory::with_mapper` uses its page tables afterwards.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that
+    /// nothing in the user part of the previous address space is still
+    /// accessed.
+    pub unsafe fn activate(&self) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut mapper = super::MAPPER.lock();
+            let (_, flags) = Cr3::read();
+            Cr3::write(self.level_4_frame, flags);
+            *mapper = Some(OffsetPageTable::new(
+                &mut *table_ptr(self.level_4_frame),
+                super::physical_memory_offset(),
+            ));
+        });
+    }
+
+    /// Frees the user part of the address space and its level 4 table. The
+    /// frames of user pages are freed unless they are still shared with
+    /// another address space.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// address space owns its page tables (it was not returned by `current`)
+    /// and that it is not active.
+    pub unsafe fn destroy(self, frame_allocator: &mut BuddyFrameAllocator) {
+        assert!(!self.is_active(), "can't destroy the active address space");
+
+        let level_4_table = &mut *table_ptr(self.level_4_frame);
+        for index in USER_ENTRIES {
+            if let Some(frame) = table_frame(&level_4_table[index]) {
+                free_table(frame, 3, frame_allocator);
+            }
+        }
+        frame_allocator.deallocate(self.level_4_frame, 0);
+    }
+}
+
+/// Returns a pointer to the page table in `frame`.
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    (super::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Returns the frame of the next level table the entry points to, `None`
+/// if the entry is unused or maps a huge page.
+fn table_frame(entry: &PageTableEntry) -> Option<PhysFrame> {
+    let flags = entry.flags();
+    if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+        Some(PhysFrame::containing_address(entry.addr()))
+    } else {
+        None
+    }
+}
+
+/// Calls `f` for every 4 KiB page mapped in the user part of the tables of
+/// `mapper`. `f` gets the mapper passed and may change the tables through
+/// it, so the entries are copied before `f` is called.
+fn for_each_user_page(
+    mapper: &mut OffsetPageTable,
+    f: &mut impl FnMut(&mut OffsetPageTable, Page) -> Result<(), AddressSpaceError>,
+) -> Result<(), AddressSpaceError> {
+    for index in USER_ENTRIES {
+        let entry = mapper.level_4_table()[index].clone();
+        if let Some(frame) = table_frame(&entry) {
+            walk(mapper, frame, 3, (index as u64) << 39, f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Calls `f` for every 4 KiB page mapped by the table in `frame` of the
+/// given level, which maps the addresses starting at `start`.
+fn walk(
+    mapper: &mut OffsetPageTable,
+    frame: PhysFrame,
+    level: u8,
+    start: u64,
+    f: &mut impl FnMut(&mut OffsetPageTable, Page) -> Result<(), AddressSpaceError>,
+) -> Result<(), AddressSpaceError> {
+    let entry_size = 4096u64 << (9 * (level - 1));
+    for index in 0..512 {
+        // No reference to the table is held while `f` runs
+        let entry = unsafe { &*table_ptr(frame) }[index].clone();
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let addr = VirtAddr::new(start + index as u64 * entry_size);
+        if level == 1 {
+            f(mapper, Page::containing_address(addr))?;
+        } else {
+            let next = table_frame(&entry).ok_or(AddressSpaceError::HugePage(addr))?;
+            walk(mapper, next, level - 1, addr.as_u64(), f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Frees the page table in `frame` of the given level, all tables below it
+/// and the frames of the pages it maps.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the
+/// tables are no longer in use.
+unsafe fn free_table(frame: PhysFrame, level: u8, frame_allocator: &mut BuddyFrameAllocator) {
+    let table = &*table_ptr(frame);
+    for entry in table.iter() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level == 1 {
+            let page_frame = PhysFrame::containing_address(entry.addr());
+            if cow::release(page_frame) {
+                frame_allocator.deallocate(page_frame, 0);
+            }
+        } else if let Some(next) = table_frame(entry) {
+            free_table(next, level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate(frame, 0);
+}
+
+#[test_case]
+fn test_new_address_space_shares_kernel() {
+    use x86_64::structures::paging::Translate;
+
+    let used_frames = super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    let mut space = super::with_frame_allocator(AddressSpace::new)
+        .expect("creating the address space failed");
+    assert!(!space.is_active());
+
+    // The VGA buffer and the kernel code are mapped the same way
+    let code = VirtAddr::new(AddressSpace::new as *const () as u64);
+    for addr in [VirtAddr::new(0xb8000), code] {
+        let kernel = super::with_mapper(|mapper| mapper.translate_addr(addr));
+        assert_eq!(space.mapper().translate_addr(addr), kernel);
+    }
+    assert_eq!(space.mapper().translate_addr(VirtAddr::new(USER_START)), None);
+
+    super::with_frame_allocator(|frame_allocator| unsafe { space.destroy(frame_allocator) });
+    assert_eq!(
+        super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames()),
+        used_frames
+    );
+}
+
+#[test_case]
+fn test_later_kernel_mappings_are_shared() {
+    use x86_64::structures::paging::Translate;
+
+    let mut space = super::with_frame_allocator(AddressSpace::new)
+        .expect("creating the address space failed");
+
+    // A kernel page below a level 4 entry nothing used before, mapped
+    // after the address space was created
+    let page: Page = Page::containing_address(VirtAddr::new(0x_7000_0000_0000));
+    let frame = super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            let frame = frame_allocator.allocate_frame().expect("no frames left");
+            let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+            unsafe {
+                super::map_page(page, frame, flags, mapper, frame_allocator)
+                    .expect("map_page failed");
+            }
+            frame
+        })
+    });
+    assert_eq!(
+        space.mapper().translate_addr(page.start_address()),
+        Some(frame.start_address())
+    );
+
+    super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| unsafe {
+            let frame = super::unmap_page(page, mapper).expect("unmap failed");
+            frame_allocator.deallocate(frame, 0);
+            space.destroy(frame_allocator);
+        })
+    });
+}
+
+#[test_case]
+fn test_cloned_address_spaces_are_isolated() {
+    use super::user::{copy_from_user, copy_to_user};
+    use x86_64::structures::paging::Mapper;
+
+    let used_frames = super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    let kernel = AddressSpace::current();
+    let addr = VirtAddr::new(USER_START);
+    let mut buf = [0; 6];
+
+    // A parent with a single user page
+    let mut parent = super::with_frame_allocator(|frame_allocator| {
+        let mut parent = AddressSpace::new(frame_allocator).expect("creating the parent failed");
+        let frame = frame_allocator.allocate_frame().expect("no frames left");
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            parent
+                .mapper()
+                .map_to(Page::containing_address(addr), frame, flags, frame_allocator)
+                .expect("mapping the user page failed")
+                .ignore();
+        }
+        parent
+    });
+    unsafe { parent.activate() };
+    copy_to_user(addr, b"parent").expect("copy_to_user failed");
+
+    let child = super::with_frame_allocator(|frame_allocator| parent.clone_user(frame_allocator))
+        .expect("cloning the address space failed");
+    unsafe { child.activate() };
+    copy_from_user(&mut buf, addr).expect("copy_from_user failed");
+    assert_eq!(&buf, b"parent");
+
+    // The write copies the page into the child
+    copy_to_user(addr, b"child!").expect("copy_to_user failed");
+    copy_from_user(&mut buf, addr).expect("copy_from_user failed");
+    assert_eq!(&buf, b"child!");
+
+    unsafe { parent.activate() };
+    copy_from_user(&mut buf, addr).expect("copy_from_user failed");
+    assert_eq!(&buf, b"parent");
+
+    unsafe { kernel.activate() };
+    super::with_frame_allocator(|frame_allocator| unsafe {
+        child.destroy(frame_allocator);
+        parent.destroy(frame_allocator);
+    });
+    assert_eq!(
+        super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames()),
+        used_frames
+    );
+}
+
+#[test_case]
+fn test_failed_clone_is_rolled_back() {
+    use x86_64::PhysAddr;
+    use x86_64::structures::paging::{Mapper, Size2MiB};
+
+    let used_frames = super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+
+    // A user page that is shared before the walk reaches a huge page,
+    // which can't be cloned. The huge page is never accessed.
+    let huge_page = Page::<Size2MiB>::containing_address(VirtAddr::new(USER_START + 0x20_0000));
+    let (mut parent, frame) = super::with_frame_allocator(|frame_allocator| {
+        let mut parent = AddressSpace::new(frame_allocator).expect("creating the parent failed");
+        let frame = frame_allocator.allocate_frame().expect("no frames left");
+        let page = Page::containing_address(VirtAddr::new(USER_START));
+        let huge_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0));
+        unsafe {
+            let mut mapper = parent.mapper();
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .expect("mapping the user page failed")
+                .ignore();
+            mapper
+                .map_to(huge_page, huge_frame, flags, frame_allocator)
+                .expect("mapping the huge page failed")
+                .ignore();
+        }
+        (parent, frame)
+    });
+
+    let result = super::with_frame_allocator(|frame_allocator| parent.clone_user(frame_allocator));
+    match result {
+        Err(AddressSpaceError::HugePage(addr)) => assert_eq!(addr, huge_page.start_address()),
+        _ => panic!("cloning a huge page did not fail"),
+    }
+    // The clone's reference to the shared page is gone again
+    assert_eq!(cow::refcount(frame), 1);
+
+    parent.mapper().unmap(huge_page).expect("unmapping the huge page failed").1.ignore();
+    super::with_frame_allocator(|frame_allocator| unsafe { parent.destroy(frame_allocator) });
+    assert_eq!(
+        super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames()),
+        used_frames
+    );
+}
@@ -0,0 +1,266 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+
+/// Largest supported block order, a block of order `n` consists of
+/// `2^n` physically contiguous frames (order 10 = 4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Header stored at the start of every free block, it links the block
+/// into the free list of its order.
+struct FreeBlock {
+    next: Option<PhysAddr>,
+}
+
+/// A physical frame allocator based on the buddy system.
+///
+/// Free memory is kept in blocks of `2^order` frames, one free list per
+/// order. Allocations split larger blocks in halves (buddies) until the
+/// requested order is reached, deallocations merge a block with its buddy
+/// again as long as the buddy is free as well. The free lists are stored
+/// in the free frames themselves, which are accessed through the physical
+/// memory mapping.
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    /// Start address of the first free block of each order
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1],
+    total_frames: usize,
+    free_frames: usize,
+    /// Frames of the memory map that are not usable (firmware, kernel,
+    /// bootloader, ...)
+    reserved_frames: usize,
+    /// End of the highest usable region
+    usable_end: PhysAddr,
+}
+
+impl BuddyFrameAllocator {
+    /// Create a BuddyFrameAllocator from the usable regions of the passed
+    /// memory map.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, that all frames marked as `USABLE` are really unused
+    /// and that the complete physical memory is mapped at `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [None; MAX_ORDER + 1],
+            total_frames: 0,
+            free_frames: 0,
+            reserved_frames: 0,
+            usable_end: PhysAddr::new(0),
+        };
+
+        allocator.reserved_frames = memory_map
+            .iter()
+            .filter(|r| r.region_type != MemoryRegionType::Usable)
+            .map(|r| ((r.range.end_addr() - r.range.start_addr()) / FRAME_SIZE) as usize)
+            .sum();
+
+        let usable_regions = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        for region in usable_regions {
+            let mut addr = region.range.start_addr();
+            let end = region.range.end_addr();
+            allocator.usable_end = allocator.usable_end.max(PhysAddr::new(end));
+            // Split the region into the largest blocks that are
+            // aligned to their own size
+            while addr < end {
+                let mut order = MAX_ORDER;
+                while addr % (FRAME_SIZE << order) != 0 || addr + (FRAME_SIZE << order) > end {
+                    order -= 1;
+                }
+                allocator.total_frames += 1 << order;
+                allocator.free_block(PhysAddr::new(addr), order);
+                addr += FRAME_SIZE << order;
+            }
+        }
+
+        allocator
+    }
+
+    /// Allocates `2^order` physically contiguous frames and returns the
+    /// first one. The returned frame is aligned to the size of the block.
+    pub fn allocate_contiguous(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // Find the smallest non-empty free list that can serve the
+        // request
+        let mut current_order = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let block = unsafe { self.pop(current_order) }?;
+
+        // Split the block until it has the requested size, the upper
+        // halves go back to the free lists
+        while current_order > order {
+            current_order -= 1;
+            let buddy = block + (FRAME_SIZE << current_order);
+            unsafe { self.push(buddy, current_order) };
+        }
+
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(block))
+    }
+
+    /// Returns a block of `2^order` frames starting at `frame` to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the block
+    /// was returned by `allocate_contiguous` with the same order and that it is
+    /// no longer in use.
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        self.free_block(frame.start_address(), order);
+    }
+
+    /// Number of frames managed by the allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames that are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Number of frames in the memory map that are not managed by the
+    /// allocator, because they are reserved or were already in use at boot.
+    pub fn reserved_frames(&self) -> usize {
+        self.reserved_frames
+    }
+
+    /// End address of the highest frame the allocator can hand out.
+    pub fn usable_end(&self) -> PhysAddr {
+        self.usable_end
+    }
+
+    /// Inserts the block into the free lists, merging it with its buddy
+    /// for as long as possible.
+    unsafe fn free_block(&mut self, mut addr: PhysAddr, mut order: usize) {
+        self.free_frames += 1 << order;
+
+        while order < MAX_ORDER {
+            // The buddy of a block differs only in the bit of the
+            // block size
+            let buddy = PhysAddr::new(addr.as_u64() ^ (FRAME_SIZE << order));
+            if !self.remove(buddy, order) {
+                break;
+            }
+            // The merged block starts at the lower of both buddies
+            if buddy < addr {
+                addr = buddy;
+            }
+            order += 1;
+        }
+
+        self.push(addr, order);
+    }
+
+    /// Returns a pointer to the free block header at the given address.
+    fn header(&self, addr: PhysAddr) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr.as_u64()).as_mut_ptr()
+    }
+
+    /// Adds the block at the front of the free list of the given order.
+    unsafe fn push(&mut self, addr: PhysAddr, order: usize) {
+        self.header(addr).write(FreeBlock {
+            next: self.free_lists[order].take(),
+        });
+        self.free_lists[order] = Some(addr);
+    }
+
+    /// Removes the first block from the free list of the given order.
+    unsafe fn pop(&mut self, order: usize) -> Option<PhysAddr> {
+        let addr = self.free_lists[order]?;
+        self.free_lists[order] = (*self.header(addr)).next;
+        Some(addr)
+    }
+
+    /// Removes the block with the given address from the free list of the
+    /// given order, returns `false` if the block is not in the list.
+    unsafe fn remove(&mut self, addr: PhysAddr, order: usize) -> bool {
+        let mut previous: Option<PhysAddr> = None;
+        let mut current = self.free_lists[order];
+
+        while let Some(block) = current {
+            let next = (*self.header(block)).next;
+            if block == addr {
+                match previous {
+                    Some(previous) => (*self.header(previous)).next = next,
+                    None => self.free_lists[order] = next,
+                }
+                return true;
+            }
+            previous = current;
+            current = next;
+        }
+
+        false
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame, 0);
+    }
+}
+
+#[test_case]
+fn test_allocate_contiguous_is_aligned() {
+    super::with_frame_allocator(|frame_allocator| {
+        let free_before = frame_allocator.free_frames();
+        let block = frame_allocator.allocate_contiguous(3).expect("no block of order 3 left");
+        assert_eq!(block.start_address().as_u64() % (FRAME_SIZE << 3), 0);
+        assert_eq!(frame_allocator.free_frames(), free_before - 8);
+
+        unsafe { frame_allocator.deallocate(block, 3) };
+        assert_eq!(frame_allocator.free_frames(), free_before);
+    });
+}
+
+#[test_case]
+fn test_deallocate_merges_buddies() {
+    super::with_frame_allocator(|frame_allocator| {
+        let used_before = frame_allocator.used_frames();
+        let block = frame_allocator.allocate_contiguous(1).expect("no block of order 1 left");
+        unsafe { frame_allocator.deallocate(block, 1) };
+        assert_eq!(frame_allocator.used_frames(), used_before);
+
+        // The freed block has been merged with its buddy again, so the
+        // same block is handed out for the same request
+        let again = frame_allocator.allocate_contiguous(1).expect("no block of order 1 left");
+        assert_eq!(again, block);
+        unsafe { frame_allocator.deallocate(again, 1) };
+    });
+}
+
+#[test_case]
+fn test_single_frames_are_distinct() {
+    super::with_frame_allocator(|frame_allocator| {
+        let a = frame_allocator.allocate_frame().expect("no frames left");
+        let b = frame_allocator.allocate_frame().expect("no frames left");
+        assert_ne!(a, b);
+        assert!(frame_allocator.allocate_contiguous(MAX_ORDER + 1).is_none());
+
+        unsafe {
+            frame_allocator.deallocate_frame(a);
+            frame_allocator.deallocate_frame(b);
+        }
+    });
+}
@@ -0,0 +1,416 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+use spin::Once;
+use x86_64::VirtAddr;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    mapper::{MapToError, MappedFrame, TranslateResult},
+};
+use super::buddy::{BuddyFrameAllocator, MAX_ORDER};
+
+/// Software defined page table bit that marks a page as copy-on-write. The
+/// CPU ignores it, it only tells the page fault handler that a write to the
+/// read-only page should copy it.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Number of frames whose reference counts fit into the largest block of
+/// the frame allocator (8 GiB of physical memory).
+const CHUNK_FRAMES: usize = (4096 << MAX_ORDER) / core::mem::size_of::<AtomicU16>();
+
+/// Number of pages mapping each physical frame, in chunks of
+/// `CHUNK_FRAMES` frames indexed by frame number. Only frames shared with
+/// `map_cow` are counted, 0 means the frame is not shared.
+static REFCOUNTS: Once<&'static [&'static [AtomicU16]]> = Once::new();
+
+/// Errors that can occur when sharing a page.
+#[derive(Debug)]
+pub enum CowError {
+    /// The page is not mapped, or mapped with a huge page
+    NotMapped,
+    /// The frame lies above the memory of the frame allocator, so its
+    /// references can't be counted
+    NotTracked,
+    /// The frame is already shared by the maximum number of pages
+    TooManyReferences,
+    /// Mapping the shared frame failed
+    MapFailed(MapToError<Size4KiB>),
+}
+
+/// Allocates the reference counts for all frames of the frame allocator.
+/// Must be called once before any page is shared.
+pub fn init(frame_allocator: &mut BuddyFrameAllocator) {
+    let frames = (frame_allocator.usable_end().as_u64() / 4096) as usize;
+    let chunk_count = frames.div_ceil(CHUNK_FRAMES);
+
+    let chunks = allocate_zeroed::<&'static [AtomicU16]>(chunk_count, frame_allocator);
+    for i in 0..chunk_count {
+        let len = CHUNK_FRAMES.min(frames - i * CHUNK_FRAMES);
+        let chunk = allocate_zeroed::<AtomicU16>(len, frame_allocator);
+        unsafe {
+            // All zero is a valid state for atomics, no frame is shared yet
+            chunks.add(i).write(core::slice::from_raw_parts(chunk, len));
+        }
+    }
+    let refcounts = unsafe { core::slice::from_raw_parts(chunks, chunk_count) };
+    REFCOUNTS.call_once(|| refcounts);
+}
+
+/// Allocates zeroed frames for `len` `T`s from the frame allocator and
+/// returns a pointer to them in the physical memory mapping.
+fn allocate_zeroed<T>(len: usize, frame_allocator: &mut BuddyFrameAllocator) -> *mut T {
+    let bytes = len * core::mem::size_of::<T>();
+    let order = (0..=MAX_ORDER)
+        .find(|order| 4096 << order >= bytes)
+        .expect("too much physical memory for the reference counts");
+    let block = frame_allocator
+        .allocate_contiguous(order)
+        .expect("no frames left for the reference counts");
+
+    let start = super::physical_memory_offset() + block.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(start.as_mut_ptr::<u8>(), 0, bytes) };
+    start.as_mut_ptr()
+}
+
+/// Returns the reference count of `frame`, or `None` if the frame lies
+/// above the memory of the frame allocator.
+fn refcount_of(frame: PhysFrame) -> Option<&'static AtomicU16> {
+    let refcounts = REFCOUNTS.get().expect("cow::init was not called");
+    let index = (frame.start_address().as_u64() / 4096) as usize;
+    refcounts.get(index / CHUNK_FRAMES)?.get(index % CHUNK_FRAMES)
+}
+
+/// Returns the number of copy-on-write pages mapping `frame`, 0 if the
+/// frame is not shared.
+pub fn refcount(frame: PhysFrame) -> usize {
+    refcount_of(frame).map_or(0, |refcount| refcount.load(Ordering::Relaxed) as usize)
+}
+
+/// Turns `page` into a copy-on-write page, it is mapped read-only and a
+/// write to it copies the frame.
+///
+/// Returns the frame of the page and the flags the frame must be shared
+/// with by `map_cow`.
+pub fn mark_cow(
+    page: Page,
+    mapper: &mut OffsetPageTable,
+) -> Result<(PhysFrame, PageTableFlags), CowError> {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => {
+            (frame, flags)
+        }
+        _ => return Err(CowError::NotMapped),
+    };
+    let refcount = refcount_of(frame).ok_or(CowError::NotTracked)?;
+
+    // The page itself is the first reference
+    let _ = refcount.compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed);
+
+    // Read-only pages are never written, so they can simply be shared
+    if !flags.contains(PageTableFlags::WRITABLE) && !flags.contains(COW) {
+        return Ok((frame, flags));
+    }
+
+    let flags = (flags - PageTableFlags::WRITABLE) | COW;
+    unsafe {
+        mapper
+            .update_flags(page, flags)
+            .map_err(|_| CowError::NotMapped)?
+            .flush();
+    }
+
+    Ok((frame, flags))
+}
+
+/// Maps `page` to the `frame` of a page that was marked with `mark_cow`,
+/// using the `flags` returned by it.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that `frame`
+/// and `flags` were returned by `mark_cow` and that the frame wasn't freed
+/// since.
+pub unsafe fn map_cow(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> Result<(), CowError> {
+    let refcount = refcount_of(frame).ok_or(CowError::NotTracked)?;
+    if refcount.load(Ordering::Relaxed) == u16::MAX {
+        return Err(CowError::TooManyReferences);
+    }
+    refcount.fetch_add(1, Ordering::Relaxed);
+
+    match mapper.map_to(page, frame, flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            refcount.fetch_sub(1, Ordering::Relaxed);
+            Err(CowError::MapFailed(err))
+        }
+    }
+}
+
+/// Drops a reference to the frame of a page that was unmapped, the frame
+/// might be shared with `map_cow`.
+///
+/// Returns `true` if no other page maps the frame anymore, the caller is
+/// then responsible for freeing it. Frames above the memory of the frame
+/// allocator are never shared.
+pub fn release(frame: PhysFrame) -> bool {
+    let refcount = match refcount_of(frame) {
+        Some(refcount) => refcount,
+        None => return true,
+    };
+    if refcount.load(Ordering::Relaxed) <= 1 {
+        refcount.store(0, Ordering::Relaxed);
+        true
+    } else {
+        refcount.fetch_sub(1, Ordering::Relaxed);
+        false
+    }
+}
+
+/// Tries to resolve a write to a copy-on-write page at `addr`. The page gets
+/// a private copy of the frame, or the frame itself if no other page maps
+/// it anymore, and is made writable again.
+///
+/// Returns `true` if the fault was resolved and the faulting instruction can
+/// be restarted.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let cow_fault = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(cow_fault) || REFCOUNTS.get().is_none() {
+        return false;
+    }
+
+    // The faulting code might hold one of the locks, in that case the
+    // fault can't be resolved without deadlocking
+    let mut mapper = match super::MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let mapper = match mapper.as_mut() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+
+    let (frame, flags) = match mapper.translate(addr) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. }
+            if flags.contains(COW) =>
+        {
+            (frame, flags)
+        }
+        _ => return false,
+    };
+    let page: Page = Page::containing_address(addr);
+    let writable = (flags - COW) | PageTableFlags::WRITABLE;
+
+    // The last page mapping the frame can take it over without copying
+    let refcount = match refcount_of(frame) {
+        Some(refcount) => refcount,
+        None => return false,
+    };
+    if refcount.load(Ordering::Relaxed) <= 1 {
+        refcount.store(0, Ordering::Relaxed);
+        return match unsafe { mapper.update_flags(page, writable) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    let mut frame_allocator = match super::FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+    let frame_allocator = match frame_allocator.as_mut() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+    let copy = match frame_allocator.allocate_contiguous(0) {
+        Some(copy) => copy,
+        None => return false,
+    };
+
+    // Copy through the physical memory mapping, the page itself is
+    // still read-only
+    let offset = super::physical_memory_offset();
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+            (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+            4096,
+        );
+    }
+
+    // Replace the shared frame with the copy, the page tables of the
+    // page exist already so no frames are allocated
+    let remapped = mapper.unmap(page).map(|(_, flush)| flush.flush()).is_ok()
+        && unsafe { mapper.map_to(page, copy, writable, frame_allocator) }
+            .map(|flush| flush.flush())
+            .is_ok();
+    if !remapped {
+        unsafe { frame_allocator.deallocate(copy, 0) };
+        return false;
+    }
+    refcount.fetch_sub(1, Ordering::Relaxed);
+    true
+}
+
+#[test_case]
+fn test_cow_page_is_copied_on_write() {
+    use x86_64::structures::paging::FrameAllocator;
+
+    let first: Page = Page::containing_address(VirtAddr::new(0x_6666_3000_0000));
+    let second: Page = Page::containing_address(VirtAddr::new(0x_6666_3010_0000));
+    let first_ptr: *mut u64 = first.start_address().as_mut_ptr();
+    let second_ptr: *mut u64 = second.start_address().as_mut_ptr();
+
+    let frame = super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            let frame = frame_allocator.allocate_frame().expect("no frames left");
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe {
+                super::map_page(first, frame, flags, mapper, frame_allocator)
+                    .expect("map_page failed");
+                first_ptr.write_volatile(42);
+            }
+
+            let (frame, flags) = mark_cow(first, mapper).expect("mark_cow failed");
+            assert!(!flags.contains(PageTableFlags::WRITABLE));
+            unsafe {
+                map_cow(second, frame, flags, mapper, frame_allocator).expect("map_cow failed");
+            }
+            frame
+        })
+    });
+    assert_eq!(refcount(frame), 2);
+    assert_eq!(unsafe { second_ptr.read_volatile() }, 42);
+
+    // The write gives the first page a private copy
+    unsafe { first_ptr.write_volatile(1) };
+    assert_eq!(unsafe { first_ptr.read_volatile() }, 1);
+    assert_eq!(unsafe { second_ptr.read_volatile() }, 42);
+    assert_eq!(refcount(frame), 1);
+    let first_frame = super::with_mapper(|mapper| mapper.translate_page(first).ok());
+    assert!(first_frame.is_some() && first_frame != Some(frame));
+
+    // The second page is the last user of the frame and takes it over
+    unsafe { second_ptr.write_volatile(2) };
+    assert_eq!(unsafe { first_ptr.read_volatile() }, 1);
+    assert_eq!(unsafe { second_ptr.read_volatile() }, 2);
+    assert_eq!(refcount(frame), 0);
+    super::with_mapper(|mapper| {
+        assert_eq!(mapper.translate_page(second).ok(), Some(frame));
+
+        super::with_frame_allocator(|frame_allocator| {
+            for page in [first, second] {
+                let frame = super::unmap_page(page, mapper).expect("unmap failed");
+                assert!(release(frame));
+                unsafe { frame_allocator.deallocate(frame, 0) };
+            }
+        })
+    });
+}
+
+#[test_case]
+fn test_read_only_pages_are_shared_directly() {
+    use x86_64::structures::paging::FrameAllocator;
+
+    let first: Page = Page::containing_address(VirtAddr::new(0x_6666_3020_0000));
+    let second: Page = Page::containing_address(VirtAddr::new(0x_6666_3030_0000));
+
+    super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            let frame = frame_allocator.allocate_frame().expect("no frames left");
+            let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+            unsafe {
+                super::map_page(first, frame, flags, mapper, frame_allocator)
+                    .expect("map_page failed");
+            }
+
+            let (shared, shared_flags) = mark_cow(first, mapper).expect("mark_cow failed");
+            assert_eq!(shared, frame);
+            assert_eq!(shared_flags, flags);
+            unsafe {
+                map_cow(second, shared, shared_flags, mapper, frame_allocator)
+                    .expect("map_cow failed");
+            }
+            assert_eq!(refcount(frame), 2);
+            assert_eq!(mapper.translate_page(second).ok(), Some(frame));
+
+            // The frame can only be freed once both pages are gone
+            let frame = super::unmap_page(first, mapper).expect("unmap failed");
+            assert!(!release(frame));
+            let frame = super::unmap_page(second, mapper).expect("unmap failed");
+            assert!(release(frame));
+            unsafe { frame_allocator.deallocate(frame, 0) };
+        })
+    });
+}
+
+#[test_case]
+fn test_untracked_frames_are_not_shared() {
+    use x86_64::PhysAddr;
+
+    // A frame far above the memory of the frame allocator
+    let usable_end = super::with_frame_allocator(|frame_allocator| frame_allocator.usable_end());
+    let frame = PhysFrame::containing_address(PhysAddr::new(usable_end.as_u64() + (64 << 30)));
+    assert_eq!(refcount(frame), 0);
+    assert!(release(frame));
+}
+
+#[test_case]
+fn test_clone_user_shares_frames() {
+    use super::address_space::{AddressSpace, USER_START};
+    use x86_64::structures::paging::FrameAllocator;
+
+    let used_frames = super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    let page: Page = Page::containing_address(VirtAddr::new(USER_START));
+
+    super::with_frame_allocator(|frame_allocator| {
+        let mut parent = AddressSpace::new(frame_allocator).expect("creating the parent failed");
+        let frame = frame_allocator.allocate_frame().expect("no frames left");
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            parent
+                .mapper()
+                .map_to(page, frame, flags, frame_allocator)
+                .expect("mapping the user page failed")
+                .ignore();
+        }
+
+        // Parent and child map the same frame read-only, with one
+        // reference each
+        let mut child = parent.clone_user(frame_allocator).expect("cloning failed");
+        for space in [&mut parent, &mut child] {
+            match space.mapper().translate(page.start_address()) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(mapped), flags, .. } => {
+                    assert_eq!(mapped, frame);
+                    assert!(flags.contains(COW));
+                    assert!(!flags.contains(PageTableFlags::WRITABLE));
+                }
+                result => panic!("user page is not mapped: {:?}", result),
+            }
+        }
+        assert_eq!(refcount(frame), 2);
+
+        // The frame stays allocated until the last address space is gone
+        unsafe { child.destroy(frame_allocator) };
+        assert_eq!(refcount(frame), 1);
+        unsafe { parent.destroy(frame_allocator) };
+        assert_eq!(refcount(frame), 0);
+    });
+    assert_eq!(
+        super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames()),
+        used_frames
+    );
+}
@@ -0,0 +1,240 @@
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags};
+
+/// Maximum number of lazy regions that can be registered at once.
+const MAX_LAZY_REGIONS: usize = 16;
+
+/// All registered lazy regions.
+static REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    Mutex::new([None; MAX_LAZY_REGIONS]);
+
+/// A virtual memory region whose pages are only mapped on their first
+/// access.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    name: &'static str,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+impl LazyRegion {
+    /// Name of the region, used for diagnostics.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// First address of the region.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Address right after the region.
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    /// Returns `true` if `addr` lies within the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+/// Errors that can occur when registering a lazy region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyRegionError {
+    /// Start or size of the region are not page aligned, or the size is 0
+    NotPageAligned,
+    /// The region overlaps with an already registered region
+    Overlapping,
+    /// All region slots are in use
+    TooManyRegions,
+}
+
+/// Registers the region of `size` bytes at `start` for demand paging.
+///
+/// Every page of the region that is accessed while not being present is
+/// backed by a zeroed frame which is mapped with `flags`. The region must
+/// not overlap with any existing mapping.
+pub fn register_lazy_region(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<LazyRegion, LazyRegionError> {
+    if !start.is_aligned(4096u64) || size == 0 || !size.is_multiple_of(4096) {
+        return Err(LazyRegionError::NotPageAligned);
+    }
+
+    let region = LazyRegion {
+        name,
+        start,
+        end: start + size,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let overlapping = regions
+            .iter()
+            .flatten()
+            .any(|r| r.start < region.end && region.start < r.end);
+        if overlapping {
+            return Err(LazyRegionError::Overlapping);
+        }
+
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(LazyRegionError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(region)
+    })
+}
+
+/// Removes the registration of the region starting at `start` and unmaps
+/// the pages that were mapped on access, their frames are returned to the
+/// frame allocator.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the
+/// region is no longer accessed.
+pub unsafe fn unregister_lazy_region(start: VirtAddr) -> Option<LazyRegion> {
+    let region = x86_64::instructions::interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .iter_mut()
+            .find(|r| matches!(r, Some(r) if r.start == start))
+            .and_then(|r| r.take())
+    })?;
+
+    let first: Page = Page::containing_address(region.start);
+    let pages = Page::range(first, Page::containing_address(region.end));
+    super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            for page in pages {
+                // Pages that were never accessed are not mapped
+                if let Ok(frame) = super::unmap_page(page, mapper) {
+                    frame_allocator.deallocate(frame, 0);
+                }
+            }
+        })
+    });
+    Some(region)
+}
+
+/// Returns the registered region containing `addr`.
+pub fn lazy_region(addr: VirtAddr) -> Option<LazyRegion> {
+    // Called from the page fault handler, so don't spin on the lock
+    let regions = REGIONS.try_lock()?;
+    regions.iter().flatten().find(|r| r.contains(addr)).copied()
+}
+
+/// Tries to resolve a page fault at `addr` by mapping a zeroed frame, if the
+/// address lies within a lazy region and the page is not present yet.
+///
+/// Returns `true` if the page was mapped and the faulting instruction can be
+/// restarted.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // Only faults on non-present pages can be resolved, protection
+    // violations are real errors
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let region = match lazy_region(addr) {
+        Some(region) => region,
+        None => return false,
+    };
+
+    // The faulting code might hold one of the locks, in that case the
+    // fault can't be resolved without deadlocking
+    let mut mapper = match super::MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let mut frame_allocator = match super::FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    // Zero the frame through the physical memory mapping before it
+    // becomes visible
+    let frame_ptr: *mut u8 =
+        (super::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { core::ptr::write_bytes(frame_ptr, 0, 4096) };
+
+    let page: Page = Page::containing_address(addr);
+    match unsafe { mapper.map_to(page, frame, region.flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate(frame, 0) };
+            false
+        }
+    }
+}
+
+#[test_case]
+fn test_lazy_region_is_mapped_on_access() {
+    use x86_64::structures::paging::Translate;
+
+    let start = VirtAddr::new(0x_6666_0000_0000);
+    let flags = PageTableFlags::WRITABLE;
+    let region = register_lazy_region("test region", start, 4 * 4096, flags)
+        .expect("registering the lazy region failed");
+    assert_eq!(lazy_region(start + 4096u64).map(|r| r.name()), Some("test region"));
+
+    let second_page = start + 4096u64;
+    assert_eq!(super::with_mapper(|mapper| mapper.translate_addr(second_page)), None);
+
+    // The first access faults and maps a zeroed page, the write is
+    // repeated afterwards
+    let ptr: *mut u64 = (second_page + 8u64).as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(super::with_mapper(|mapper| mapper.translate_addr(second_page)).is_some());
+    // Only the accessed page was mapped
+    assert_eq!(super::with_mapper(|mapper| mapper.translate_addr(start)), None);
+
+    // The frame of the accessed page is freed with the region
+    let used_frames = super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    assert!(unsafe { unregister_lazy_region(region.start()) }.is_some());
+    assert!(lazy_region(start).is_none());
+    assert_eq!(super::with_mapper(|mapper| mapper.translate_addr(second_page)), None);
+    assert_eq!(
+        super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames()),
+        used_frames - 1
+    );
+}
+
+#[test_case]
+fn test_lazy_region_rejects_overlap() {
+    let start = VirtAddr::new(0x_6666_1000_0000);
+    let flags = PageTableFlags::WRITABLE;
+    register_lazy_region("first", start, 2 * 4096, flags).expect("registering failed");
+    assert_eq!(
+        register_lazy_region("second", start + 4096u64, 4096, flags).err(),
+        Some(LazyRegionError::Overlapping)
+    );
+    assert_eq!(
+        register_lazy_region("unaligned", start + 0x10_0000u64, 100, flags).err(),
+        Some(LazyRegionError::NotPageAligned)
+    );
+    unsafe { unregister_lazy_region(start) };
+}
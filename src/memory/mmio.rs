@@ -0,0 +1,253 @@
+use core::ops::{Deref, DerefMut};
+use volatile::Volatile;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use super::vmalloc::{self, VmapError};
+use crate::cpu;
+
+/// The page attribute table MSR.
+const IA32_PAT: u32 = 0x277;
+
+/// Memory types of the page attribute table entries.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+
+/// The page attribute table programmed by `init_pat`. Only the PWT and
+/// PCD bits select an entry, the PAT bit of a level 1 entry is at the
+/// position of the huge page bit of the higher levels and stays clear, so
+/// entries 4 to 7 repeat 0 to 3. Entry 1 (PWT) is write-combining, the
+/// write-through type moves from there to entry 2 (PCD), and entry 3 (PCD
+/// and PWT) stays uncacheable.
+const PAT_VALUE: u64 = PAT_WB
+    | PAT_WC << 8
+    | PAT_WT << 16
+    | PAT_UC << 24
+    | PAT_WB << 32
+    | PAT_WC << 40
+    | PAT_WT << 48
+    | PAT_UC << 56;
+
+/// Caching behavior of an MMIO mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal cached memory, only for memory without side effects
+    WriteBack,
+    /// Reads are cached, writes go directly to the device
+    WriteThrough,
+    /// Every access goes directly to the device, for registers
+    Uncacheable,
+    /// Writes are collected and sent in bursts, for framebuffers. Falls
+    /// back to `Uncacheable` if the CPU doesn't support PAT.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Returns the page table flags selecting the page attribute table
+    /// entry of the mode.
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            // Without PAT the power-on entries apply, where PWT alone is
+            // write-through
+            CacheMode::WriteThrough if cpu::has_pat() => PageTableFlags::NO_CACHE,
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if cpu::has_pat() => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncacheable | CacheMode::WriteCombining => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+/// Programs the page attribute table, so write-combining mappings can be
+/// created. Does nothing if the CPU doesn't support PAT.
+pub fn init_pat() {
+    if !cpu::has_pat() {
+        return;
+    }
+    unsafe {
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        // Caches and TLB entries might still use the old memory types
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    }
+    x86_64::instructions::tlb::flush_all();
+}
+
+/// A mapped MMIO region, viewed as a slice of volatile `T`s. The region is
+/// unmapped when it is dropped.
+#[derive(Debug)]
+pub struct Mmio<T: Copy> {
+    /// Start of the virtual area the region is mapped in
+    area_start: VirtAddr,
+    /// First `T` of the region
+    ptr: *mut Volatile<T>,
+    len: usize,
+    phys: PhysAddr,
+}
+
+// The region is owned by the view, so it can be moved to another context
+// (e.g. into a static Mutex) if T can.
+unsafe impl<T: Copy + Send> Send for Mmio<T> {}
+
+impl<T: Copy> Mmio<T> {
+    /// Physical start address of the region.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Virtual start address of the region.
+    pub fn virt_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.ptr)
+    }
+}
+
+impl<T: Copy> Deref for Mmio<T> {
+    type Target = [Volatile<T>];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T: Copy> DerefMut for Mmio<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<T: Copy> Drop for Mmio<T> {
+    fn drop(&mut self) {
+        // The frames belong to the device, vunmap leaves them alone
+        unsafe { vmalloc::vunmap(self.area_start).expect("MMIO area was unmapped twice") };
+    }
+}
+
+/// Maps the `len` bytes of device memory at `phys` with the given cache
+/// mode and returns a view of them as `len / size_of::<T>()` volatile `T`s.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that `phys`
+/// is device memory that is not mapped with a different cache mode
+/// anywhere else and that it is aligned for `T`. `T` must not be zero-sized.
+pub unsafe fn map_mmio<T: Copy>(
+    phys: PhysAddr,
+    len: usize,
+    cache_mode: CacheMode,
+) -> Result<Mmio<T>, VmapError> {
+    const { assert!(core::mem::size_of::<T>() != 0, "MMIO values must not be zero-sized") };
+    assert!(phys.is_aligned(core::mem::align_of::<T>() as u64), "MMIO region is misaligned");
+
+    let first_frame: PhysFrame = PhysFrame::containing_address(phys);
+    let offset = phys - first_frame.start_address();
+    let pages = (offset + len as u64).div_ceil(4096);
+    let area = vmalloc::reserve(pages, false)?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache_mode.flags();
+
+    let result = super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            for i in 0..pages {
+                let page: Page<Size4KiB> = area.start + i;
+                match mapper.map_to(page, first_frame + i, flags, frame_allocator) {
+                    Ok(flush) => flush.flush(),
+                    Err(err) => {
+                        vmalloc::unmap_area(area.start, i, false, mapper, frame_allocator);
+                        return Err(VmapError::Map(err));
+                    }
+                }
+            }
+            Ok(())
+        })
+    });
+
+    let area_start = area.start.start_address();
+    if let Err(err) = result {
+        vmalloc::release(area_start);
+        return Err(err);
+    }
+    Ok(Mmio {
+        area_start,
+        ptr: (area_start + offset).as_mut_ptr(),
+        len: len / core::mem::size_of::<T>(),
+        phys,
+    })
+}
+
+/// Physical base address of the local APIC registers, read from the
+/// IA32_APIC_BASE MSR.
+#[cfg(test)]
+fn local_apic_base() -> u64 {
+    unsafe { Msr::new(0x1b).read() & 0x000f_ffff_ffff_f000 }
+}
+
+#[test_case]
+fn test_map_mmio_uncacheable() {
+    use core::arch::x86_64::__cpuid;
+    use x86_64::structures::paging::{Translate, mapper::TranslateResult};
+
+    if !cpu::has_apic() {
+        return;
+    }
+    // The ID register of the local APIC, the view doesn't start at a
+    // page boundary
+    let phys = PhysAddr::new(local_apic_base() + 0x20);
+    let mmio = unsafe { map_mmio::<u32>(phys, 4, CacheMode::Uncacheable) }
+        .expect("map_mmio failed");
+    assert_eq!(mmio.len(), 1);
+    assert_eq!(mmio.phys_addr(), phys);
+
+    let virt = mmio.virt_addr();
+    super::with_mapper(|mapper| match mapper.translate(virt) {
+        TranslateResult::Mapped { flags, offset, .. } => {
+            assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+            assert_eq!(offset, 0x20);
+        }
+        result => panic!("MMIO region is not mapped: {:?}", result),
+    });
+
+    // The register holds the initial APIC ID reported by CPUID
+    let initial_id = unsafe { __cpuid(1) }.ebx >> 24;
+    assert_eq!(mmio[0].read() >> 24, initial_id);
+
+    drop(mmio);
+    assert_eq!(super::with_mapper(|mapper| mapper.translate_addr(virt)), None);
+}
+
+#[test_case]
+fn test_pat_write_combining() {
+    use x86_64::structures::paging::{Translate, mapper::TranslateResult};
+
+    if !cpu::has_pat() {
+        assert_eq!(CacheMode::WriteCombining.flags(), CacheMode::Uncacheable.flags());
+        return;
+    }
+    assert_eq!(unsafe { Msr::new(IA32_PAT).read() }, PAT_VALUE);
+    assert_eq!(CacheMode::WriteThrough.flags(), PageTableFlags::NO_CACHE);
+
+    if !cpu::has_apic() {
+        return;
+    }
+    // The two pages above the local APIC registers, in the range the CPU
+    // reserves for interrupt messages, which nothing else maps. The view
+    // spans both pages and isn't accessed, both have to use the PAT entry.
+    let phys = PhysAddr::new(local_apic_base() + 4096);
+    let mmio = unsafe { map_mmio::<u32>(phys, 4096 + 4, CacheMode::WriteCombining) }
+        .expect("map_mmio failed");
+    assert_eq!(mmio.len(), 1025);
+    for addr in [mmio.virt_addr(), mmio.virt_addr() + 4096u64] {
+        super::with_mapper(|mapper| match mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => {
+                assert!(flags.contains(PageTableFlags::WRITE_THROUGH));
+                assert!(!flags.contains(PageTableFlags::NO_CACHE));
+                assert!(!flags.contains(PageTableFlags::HUGE_PAGE));
+            }
+            result => panic!("MMIO region is not mapped: {:?}", result),
+        });
+    }
+}
@@ -0,0 +1,187 @@
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr0Flags, Efer, EferFlags};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB, Translate,
+    mapper::{FlagUpdateError, TranslateResult},
+};
+
+/// ELF magic number at the start of the ELF header
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+/// Program header type of loadable segments
+const PT_LOAD: u32 = 1;
+/// Segment permission flags
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+extern "C" {
+    /// Start of the ELF header, defined by the linker. The header and the
+    /// program headers are part of the first loaded segment.
+    static __ehdr_start: ElfHeader;
+}
+
+/// The 64-bit ELF file header.
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+/// A 64-bit ELF program header, describing one segment.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+impl ProgramHeader {
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::containing_address(VirtAddr::new(self.vaddr));
+        let end = Page::containing_address(VirtAddr::new(self.vaddr + self.memsz - 1));
+        Page::range_inclusive(start, end)
+    }
+
+    fn contains(&self, page: Page) -> bool {
+        let start = VirtAddr::new(self.vaddr);
+        let end = start + self.memsz;
+        page.start_address() < end && start < page.start_address() + page.size()
+    }
+}
+
+/// Returns the loadable segments of the running kernel.
+fn kernel_segments() -> impl Iterator<Item = ProgramHeader> {
+    let header = unsafe { &__ehdr_start };
+    assert_eq!(header.ident[..4], ELF_MAGIC, "kernel ELF header not found");
+
+    let program_headers = unsafe {
+        let start = (header as *const ElfHeader as *const u8).add(header.phoff as usize);
+        core::slice::from_raw_parts(start as *const ProgramHeader, header.phnum as usize)
+    };
+    program_headers
+        .iter()
+        .filter(|ph| ph.segment_type == PT_LOAD && ph.memsz > 0)
+        .copied()
+}
+
+/// Errors that can occur when protecting the kernel.
+#[derive(Debug)]
+pub enum ProtectError {
+    /// The page is shared by a writable and an executable segment, so it
+    /// can't be mapped W^X. The linker script has to align the segments to
+    /// pages.
+    WritableAndExecutable(Page),
+    /// Updating the flags of a page failed
+    FlagUpdate(FlagUpdateError),
+}
+
+/// Returns whether `page` has to be writable and executable, a page that
+/// is shared by multiple segments needs the permissions of all of them.
+fn page_permissions(page: Page) -> (bool, bool) {
+    kernel_segments()
+        .filter(|s| s.contains(page))
+        .fold((false, false), |(w, x), s| {
+            (w || s.flags & PF_W != 0, x || s.flags & PF_X != 0)
+        })
+}
+
+/// Enforce W^X on the kernel: code is mapped read-execute, read-only data
+/// read-only and no-execute, and writable data (`.data`, `.bss`) read-write
+/// and no-execute.
+///
+/// The section headers are not loaded into memory, so the permissions are
+/// taken from the loadable segments, which group the sections by their
+/// permissions. Also enables the no-execute bit (EFER.NXE) and write
+/// protection for supervisor mode (CR0.WP), without it the kernel could
+/// still write to read-only pages.
+///
+/// Fails without changing any mapping if a page would have to be both
+/// writable and executable.
+pub fn protect_kernel(mapper: &mut OffsetPageTable) -> Result<(), ProtectError> {
+    for segment in kernel_segments() {
+        if let Some(page) = segment.pages().find(|&page| page_permissions(page) == (true, true)) {
+            return Err(ProtectError::WritableAndExecutable(page));
+        }
+    }
+
+    // The NX bit is reserved and causes page faults unless NXE is set
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+
+    for segment in kernel_segments() {
+        for page in segment.pages() {
+            let (writable, executable) = page_permissions(page);
+            let mut flags = match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => flags,
+                _ => return Err(ProtectError::FlagUpdate(FlagUpdateError::PageNotMapped)),
+            };
+            flags.set(PageTableFlags::WRITABLE, writable);
+            flags.set(PageTableFlags::NO_EXECUTE, !executable);
+            unsafe {
+                Mapper::<Size4KiB>::update_flags(mapper, page, flags)
+                    .map_err(ProtectError::FlagUpdate)?
+                    .flush();
+            }
+        }
+    }
+
+    unsafe {
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    Ok(())
+}
+
+#[test_case]
+fn test_kernel_code_is_not_writable() {
+    let code = VirtAddr::new(protect_kernel as *const () as u64);
+    let flags = super::with_mapper(|mapper| match mapper.translate(code) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        result => panic!("kernel code is not mapped: {:?}", result),
+    });
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn test_kernel_data_is_not_executable() {
+    static mut DATA: u64 = 0;
+    static RODATA: u64 = 0;
+
+    let data = VirtAddr::new(unsafe { core::ptr::addr_of!(DATA) } as u64);
+    let rodata = VirtAddr::new(&RODATA as *const u64 as u64);
+    super::with_mapper(|mapper| {
+        match mapper.translate(data) {
+            TranslateResult::Mapped { flags, .. } => {
+                assert!(flags.contains(PageTableFlags::WRITABLE));
+                assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+            }
+            result => panic!("kernel data is not mapped: {:?}", result),
+        }
+        match mapper.translate(rodata) {
+            TranslateResult::Mapped { flags, .. } => {
+                assert!(!flags.contains(PageTableFlags::WRITABLE));
+                assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+            }
+            result => panic!("kernel rodata is not mapped: {:?}", result),
+        }
+    });
+}
@@ -0,0 +1,308 @@
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr::NonNull;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::PhysFrame;
+use super::buddy::MAX_ORDER;
+
+/// Minimum number of objects a single slab should hold, larger objects
+/// get slabs of multiple frames.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// A free object slot, stored in the slot itself.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Links the slabs of a cache, stored at the end of each slab behind its
+/// objects.
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+}
+
+/// A cache of equally sized objects of type `T`.
+///
+/// Objects are carved out of slabs of physically contiguous frames taken
+/// from the kernel's frame allocator and accessed through the physical
+/// memory mapping. Freed objects are kept in a free list and handed out
+/// again by the next `alloc`, the slabs are returned to the frame
+/// allocator when the cache is dropped.
+pub struct SlabCache<T> {
+    /// First free object slot of all slabs
+    free_list: Option<NonNull<FreeObject>>,
+    /// Header of the most recently allocated slab
+    slab_list: Option<NonNull<SlabHeader>>,
+    slabs: usize,
+    in_use: usize,
+    allocations: usize,
+    frees: usize,
+    _marker: PhantomData<T>,
+}
+
+// The cache only hands out pointers to memory it owns, so it can be
+// moved to another context (e.g. into a static Mutex) if T can.
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+/// Statistics of a single slab cache.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    /// Name of the cached type
+    pub name: &'static str,
+    /// Size of a single object slot in bytes
+    pub object_size: usize,
+    /// Number of slabs allocated from the frame allocator
+    pub slabs: usize,
+    /// Number of objects that fit into all slabs
+    pub capacity: usize,
+    /// Number of objects currently handed out
+    pub in_use: usize,
+    /// Number of `alloc` calls since the cache was created
+    pub allocations: usize,
+    /// Number of `free` calls since the cache was created
+    pub frees: usize,
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "slab {} ({} bytes): {} slabs, {}/{} objects in use, {} allocs, {} frees",
+            self.name,
+            self.object_size,
+            self.slabs,
+            self.in_use,
+            self.capacity,
+            self.allocations,
+            self.frees
+        )
+    }
+}
+
+impl<T> SlabCache<T> {
+    /// Size of a single object slot, it must be able to hold either a `T`
+    /// or a free list node.
+    const OBJECT_SIZE: usize = {
+        let size = if mem::size_of::<T>() > mem::size_of::<FreeObject>() {
+            mem::size_of::<T>()
+        } else {
+            mem::size_of::<FreeObject>()
+        };
+        let align = Self::OBJECT_ALIGN;
+        (size + align - 1) & !(align - 1)
+    };
+
+    /// Alignment of a single object slot.
+    const OBJECT_ALIGN: usize = if mem::align_of::<T>() > mem::align_of::<FreeObject>() {
+        mem::align_of::<T>()
+    } else {
+        mem::align_of::<FreeObject>()
+    };
+
+    /// Order of the frame blocks used as slabs.
+    const SLAB_ORDER: usize = {
+        let size = MIN_OBJECTS_PER_SLAB * Self::OBJECT_SIZE + mem::size_of::<SlabHeader>();
+        let mut order = 0;
+        while order < MAX_ORDER && (4096 << order) < size {
+            order += 1;
+        }
+        order
+    };
+
+    /// Size of a single slab in bytes.
+    const SLAB_SIZE: usize = 4096 << Self::SLAB_ORDER;
+
+    /// Number of objects in a single slab, the slab header follows them.
+    const OBJECTS_PER_SLAB: usize =
+        (Self::SLAB_SIZE - mem::size_of::<SlabHeader>()) / Self::OBJECT_SIZE;
+
+    /// Creates an empty cache, slabs are allocated on the first `alloc`.
+    pub const fn new() -> Self {
+        // Types that don't fit into the largest block of the frame
+        // allocator are rejected at compile time
+        const {
+            assert!(Self::OBJECT_ALIGN <= 4096, "slab objects can be at most page aligned");
+            assert!(
+                Self::OBJECT_SIZE + mem::size_of::<SlabHeader>() <= 4096 << MAX_ORDER,
+                "slab objects must fit into a block of the frame allocator"
+            );
+        }
+
+        SlabCache {
+            free_list: None,
+            slab_list: None,
+            slabs: 0,
+            in_use: 0,
+            allocations: 0,
+            frees: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Allocates uninitialized memory for a single `T`.
+    ///
+    /// Returns `None` if the frame allocator is out of memory.
+    pub fn alloc(&mut self) -> Option<NonNull<T>> {
+        if self.free_list.is_none() {
+            self.grow()?;
+        }
+
+        let object = self.free_list?;
+        self.free_list = unsafe { object.as_ref().next };
+        self.in_use += 1;
+        self.allocations += 1;
+        Some(object.cast())
+    }
+
+    /// Returns the object at `ptr` to the cache, the object is not dropped.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was returned by `alloc` of this cache and that it is no longer used.
+    pub unsafe fn free(&mut self, ptr: NonNull<T>) {
+        let object: NonNull<FreeObject> = ptr.cast();
+        object.as_ptr().write(FreeObject {
+            next: self.free_list,
+        });
+        self.free_list = Some(object);
+        self.in_use -= 1;
+        self.frees += 1;
+    }
+
+    /// Returns the current statistics of the cache.
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: core::any::type_name::<T>(),
+            object_size: Self::OBJECT_SIZE,
+            slabs: self.slabs,
+            capacity: self.slabs * Self::OBJECTS_PER_SLAB,
+            in_use: self.in_use,
+            allocations: self.allocations,
+            frees: self.frees,
+        }
+    }
+
+    /// Allocates a new slab and adds all of its objects to the free list.
+    fn grow(&mut self) -> Option<()> {
+        let frame = super::with_frame_allocator(|frame_allocator| {
+            frame_allocator.allocate_contiguous(Self::SLAB_ORDER)
+        })?;
+        let slab_start = super::physical_memory_offset() + frame.start_address().as_u64();
+
+        // Push the objects in reverse order, so they are handed out
+        // in ascending address order
+        for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object = (slab_start + i * Self::OBJECT_SIZE).as_mut_ptr::<FreeObject>();
+            unsafe {
+                object.write(FreeObject {
+                    next: self.free_list,
+                });
+                self.free_list = Some(NonNull::new_unchecked(object));
+            }
+        }
+
+        let header = (slab_start + Self::SLAB_SIZE - mem::size_of::<SlabHeader>())
+            .as_mut_ptr::<SlabHeader>();
+        unsafe {
+            header.write(SlabHeader {
+                next: self.slab_list,
+            });
+            self.slab_list = Some(NonNull::new_unchecked(header));
+        }
+        self.slabs += 1;
+
+        Some(())
+    }
+}
+
+impl<T> Drop for SlabCache<T> {
+    /// Returns the slabs to the frame allocator. If objects are still in
+    /// use, the slabs are leaked instead so the objects stay valid.
+    fn drop(&mut self) {
+        if self.in_use > 0 {
+            return;
+        }
+
+        let mut slab_list = self.slab_list.take();
+        while let Some(header) = slab_list {
+            slab_list = unsafe { header.as_ref().next };
+            let header_offset = (Self::SLAB_SIZE - mem::size_of::<SlabHeader>()) as u64;
+            let slab_start = header.as_ptr() as u64 - header_offset;
+            let physical_start = slab_start - super::physical_memory_offset().as_u64();
+            let frame = PhysFrame::containing_address(PhysAddr::new(physical_start));
+            super::with_frame_allocator(|frame_allocator| unsafe {
+                frame_allocator.deallocate(frame, Self::SLAB_ORDER)
+            });
+        }
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_slab_alloc_free() {
+    let mut cache = SlabCache::<[u64; 3]>::new();
+
+    let a = cache.alloc().expect("slab allocation failed");
+    let b = cache.alloc().expect("slab allocation failed");
+    assert_ne!(a, b);
+    unsafe {
+        a.as_ptr().write([1, 2, 3]);
+        b.as_ptr().write([4, 5, 6]);
+        assert_eq!(a.as_ptr().read(), [1, 2, 3]);
+        assert_eq!(b.as_ptr().read(), [4, 5, 6]);
+    }
+
+    let stats = cache.stats();
+    assert_eq!(stats.slabs, 1);
+    assert_eq!(stats.in_use, 2);
+    assert_eq!(stats.object_size, 24);
+
+    unsafe { cache.free(a) };
+    // The most recently freed object is reused first
+    assert_eq!(cache.alloc(), Some(a));
+
+    unsafe {
+        cache.free(a);
+        cache.free(b);
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.in_use, 0);
+    assert_eq!(stats.allocations, 3);
+    assert_eq!(stats.frees, 3);
+}
+
+#[test_case]
+fn test_slab_grows() {
+    let used_frames = super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+
+    // 1000 byte objects need slabs of two frames, which hold 8 of them
+    let mut cache = SlabCache::<[u8; 1000]>::new();
+    assert_eq!(SlabCache::<[u8; 1000]>::OBJECTS_PER_SLAB, 8);
+
+    let mut objects = [None; 9];
+    for object in objects.iter_mut() {
+        *object = cache.alloc();
+        assert!(object.is_some());
+    }
+    assert_eq!(cache.stats().slabs, 2);
+    assert_eq!(cache.stats().capacity, 16);
+    crate::println!("{}", cache.stats());
+
+    for object in objects.iter().flatten() {
+        unsafe { cache.free(*object) };
+    }
+    assert_eq!(cache.stats().in_use, 0);
+
+    // Dropping the cache returns both slabs
+    drop(cache);
+    assert_eq!(
+        super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames()),
+        used_frames
+    );
+}
@@ -0,0 +1,195 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+};
+
+/// Start of the virtual address range the kernel stacks are placed in.
+pub const STACK_REGION_START: u64 = 0x_5555_0000_0000;
+
+/// Maximum number of stacks whose guard pages can be recognized.
+const MAX_STACKS: usize = 16;
+
+/// Number of pages at the top of a lazy stack that are mapped right away.
+/// Faults can't be resolved while the page table is locked, so the usual
+/// stack depth must not depend on them.
+const EAGER_STACK_PAGES: u64 = 4;
+
+/// Next unused virtual address in the stack region, stacks are never freed
+/// so a simple bump pointer is enough.
+static NEXT_STACK_ADDR: AtomicU64 = AtomicU64::new(STACK_REGION_START);
+
+/// All allocated stacks, used to recognize hits on their guard pages.
+static STACKS: Mutex<[Option<Stack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// A kernel owned stack with an unmapped guard page below it.
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    name: &'static str,
+    guard_page: Page,
+    top: VirtAddr,
+}
+
+impl Stack {
+    /// Name used to report overflows of this stack.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The unmapped page right below the stack, writing to it means
+    /// the stack overflowed.
+    pub fn guard_page(&self) -> Page {
+        self.guard_page
+    }
+
+    /// Lowest address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard_page.start_address() + self.guard_page.size()
+    }
+
+    /// Address right above the stack, the stack grows downwards from here.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Returns `true` if `addr` lies within the stack (not counting the
+    /// guard page).
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.bottom() && addr < self.top
+    }
+}
+
+/// Allocates a stack of `pages` pages, with an additional unmapped guard
+/// page below it.
+pub fn allocate_stack(
+    name: &'static str,
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Stack, MapToError<Size4KiB>> {
+    allocate(name, pages, pages, mapper, frame_allocator)
+}
+
+/// Allocates a stack of `pages` pages like `allocate_stack`, but only its
+/// top `EAGER_STACK_PAGES` are mapped right away. The pages below form a
+/// lazy region and are mapped by the page fault handler when the stack
+/// grows into them.
+///
+/// Interrupt stacks must not be lazy, the CPU can't deliver a page fault
+/// while switching to them.
+pub fn allocate_lazy_stack(
+    name: &'static str,
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Stack, MapToError<Size4KiB>> {
+    let mapped_pages = pages.min(EAGER_STACK_PAGES);
+    let stack = allocate(name, pages, mapped_pages, mapper, frame_allocator)?;
+    if pages > mapped_pages {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let size = (pages - mapped_pages) * 4096;
+        super::lazy::register_lazy_region(name, stack.bottom(), size, flags)
+            .expect("registering the lazy stack pages failed");
+    }
+    Ok(stack)
+}
+
+/// Reserves a guard page and `pages` pages for a stack and maps the top
+/// `mapped_pages` of them.
+fn allocate(
+    name: &'static str,
+    pages: u64,
+    mapped_pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Stack, MapToError<Size4KiB>> {
+    // Reserve the virtual range for the guard page and the stack
+    let guard_addr = NEXT_STACK_ADDR.fetch_add((pages + 1) * 4096, Ordering::Relaxed);
+    let guard_page = Page::containing_address(VirtAddr::new(guard_addr));
+    let stack_start = guard_page + 1;
+    let stack_end = stack_start + pages;
+
+    // Only map the stack itself, the guard page stays unmapped so
+    // an overflow causes a page fault
+    for page in Page::range(stack_end - mapped_pages, stack_end) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+
+    let stack = Stack {
+        name,
+        guard_page,
+        top: stack_end.start_address(),
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks
+            .iter_mut()
+            .find(|s| s.is_none())
+            .expect("too many kernel stacks");
+        *slot = Some(stack);
+    });
+
+    Ok(stack)
+}
+
+/// Returns the stack whose guard page contains `addr`, i.e. the stack that
+/// overflowed if `addr` caused a page fault.
+pub fn overflowed_stack(addr: VirtAddr) -> Option<Stack> {
+    let page = Page::containing_address(addr);
+    // Don't spin in exception handlers, the lock is only held
+    // briefly while a stack is registered
+    let stacks = STACKS.try_lock()?;
+    stacks.iter().flatten().find(|s| s.guard_page == page).copied()
+}
+
+/// Switches to the given stack and calls `f` on it.
+///
+/// # Safety
+///
+/// This function is unsafe because everything on the current stack is
+/// abandoned, the caller must make sure nothing on it is still in use.
+pub unsafe fn switch_to(stack: &Stack, f: extern "C" fn() -> !) -> ! {
+    // The stack top is page aligned, so the stack is correctly
+    // aligned for the call
+    core::arch::asm!(
+        "mov rsp, {stack_top}",
+        "call {f}",
+        stack_top = in(reg) stack.top().as_u64(),
+        f = in(reg) f,
+        options(noreturn)
+    );
+}
+
+#[test_case]
+fn test_lazy_stack_maps_pages_on_access() {
+    use x86_64::structures::paging::Translate;
+
+    let stack = super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            allocate_lazy_stack("lazy test stack", EAGER_STACK_PAGES + 2, mapper, frame_allocator)
+        })
+    })
+    .expect("stack allocation failed");
+    let translate = |addr| super::with_mapper(|mapper| mapper.translate_addr(addr));
+
+    // Only the top of the stack is mapped
+    assert!(translate(stack.top() - 8u64).is_some());
+    assert_eq!(translate(stack.bottom()), None);
+    let region = super::lazy::lazy_region(stack.bottom()).expect("no lazy region");
+    assert_eq!(region.end(), stack.top() - EAGER_STACK_PAGES * 4096);
+
+    // Growing into the lazy part maps the page
+    let ptr: *mut u64 = stack.bottom().as_mut_ptr();
+    unsafe { ptr.write_volatile(42) };
+    assert!(translate(stack.bottom()).is_some());
+}
@@ -0,0 +1,213 @@
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{OffsetPageTable, Page, PageTable, PageTableFlags};
+use super::address_space::{USER_END, USER_START};
+use crate::cpu;
+
+/// Errors that can occur when copying from or to user memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// The range is not inside the user part of the address space
+    /// (`USER_START..USER_END`), or wraps around its end
+    InvalidRange,
+    /// The page containing the address is not mapped
+    NotMapped(VirtAddr),
+    /// The page containing the address is not user accessible, or not
+    /// writable when copying to it
+    AccessDenied(VirtAddr),
+}
+
+/// Checks that every page of the `len` bytes at `addr` is mapped user
+/// accessible, and writable if `write` is set. Copy-on-write pages count
+/// as writable, the write copies them.
+fn check_user_range(addr: VirtAddr, len: usize, write: bool) -> Result<(), UserCopyError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let last = addr
+        .as_u64()
+        .checked_add(len as u64 - 1)
+        .filter(|&last| addr.as_u64() >= USER_START && last < USER_END)
+        .ok_or(UserCopyError::InvalidRange)?;
+    let last = VirtAddr::new(last);
+
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let writable = |flags: PageTableFlags| {
+        flags.contains(PageTableFlags::WRITABLE) || flags.contains(super::cow::COW)
+    };
+
+    let first: Page = Page::containing_address(addr);
+    let pages = Page::range_inclusive(first, Page::containing_address(last));
+    super::with_mapper(|mapper| {
+        for page in pages {
+            let start = page.start_address();
+            match effective_flags(mapper, start) {
+                Some(flags) if flags.contains(required) && (!write || writable(flags)) => {}
+                Some(_) => return Err(UserCopyError::AccessDenied(start)),
+                None => return Err(UserCopyError::NotMapped(start)),
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Returns the flags of the page mapping `addr`, with `USER_ACCESSIBLE` and
+/// `WRITABLE` only set if every level of the page table walk sets them, as
+/// the CPU checks them. `None` if the address is not mapped.
+fn effective_flags(mapper: &mut OffsetPageTable, addr: VirtAddr) -> Option<PageTableFlags> {
+    let offset = super::physical_memory_offset();
+    let access = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+
+    let mut table: &PageTable = mapper.level_4_table();
+    let mut allowed = access;
+    for (i, index) in indices.into_iter().enumerate() {
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        allowed &= flags;
+        // Level 1 entries and huge pages map the page
+        if i == indices.len() - 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Some((flags - access) | allowed);
+        }
+        table = unsafe { &*(offset + entry.addr().as_u64()).as_ptr::<PageTable>() };
+    }
+    None
+}
+
+/// Copies `dst.len()` bytes from the user memory at `src` into `dst`.
+///
+/// Fails without copying anything if a part of the source range is not
+/// mapped user accessible, so user space can't trick the kernel into
+/// reading kernel memory.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserCopyError> {
+    check_user_range(src, dst.len(), false)?;
+    cpu::with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len());
+    });
+    Ok(())
+}
+
+/// Copies `src` into the user memory at `dst`.
+///
+/// Fails without copying anything if a part of the destination range is
+/// not mapped user accessible and writable.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserCopyError> {
+    check_user_range(dst, src.len(), true)?;
+    cpu::with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr::<u8>(), src.len());
+    });
+    Ok(())
+}
+
+#[test_case]
+fn test_copy_from_and_to_user() {
+    use x86_64::structures::paging::{FrameAllocator, Mapper};
+
+    // Two user pages, so the copy crosses a page boundary
+    let start = VirtAddr::new(USER_START + 0x_2000_0000);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    let first_page: Page = Page::containing_address(start);
+    let pages = Page::range(first_page, first_page + 2);
+    super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            for page in pages {
+                let frame = frame_allocator.allocate_frame().expect("no frames left");
+                unsafe {
+                    super::map_page(page, frame, flags, mapper, frame_allocator)
+                        .expect("map_page failed");
+                }
+            }
+        })
+    });
+
+    let addr = start + 4090u64;
+    copy_to_user(addr, b"hello user").expect("copy_to_user failed");
+    let mut buf = [0; 10];
+    copy_from_user(&mut buf, addr).expect("copy_from_user failed");
+    assert_eq!(&buf, b"hello user");
+
+    // The range ends on an unmapped page
+    assert_eq!(
+        copy_from_user(&mut buf, start + 2 * 4096u64 - 4u64),
+        Err(UserCopyError::NotMapped(start + 2 * 4096u64))
+    );
+
+    super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            for page in pages {
+                let (frame, flush) = mapper.unmap(page).expect("unmap failed");
+                flush.flush();
+                unsafe { frame_allocator.deallocate(frame, 0) };
+            }
+        })
+    });
+}
+
+#[test_case]
+fn test_copy_rejects_kernel_memory() {
+    static SECRET: [u8; 4] = *b"key!";
+    static mut TARGET: [u8; 4] = [0; 4];
+
+    let mut buf = [0; 4];
+    let secret = VirtAddr::new(SECRET.as_ptr() as u64);
+    assert_eq!(copy_from_user(&mut buf, secret), Err(UserCopyError::InvalidRange));
+    assert_eq!(buf, [0; 4]);
+
+    let target = VirtAddr::new(unsafe { core::ptr::addr_of!(TARGET) } as u64);
+    assert_eq!(copy_to_user(target, b"evil"), Err(UserCopyError::InvalidRange));
+    assert_eq!(unsafe { TARGET }, [0; 4]);
+
+    // Ranges running out of the user part or wrapping around the end of
+    // the address space
+    assert_eq!(
+        copy_to_user(VirtAddr::new(USER_END - 4), b"too long"),
+        Err(UserCopyError::InvalidRange)
+    );
+    assert_eq!(
+        copy_to_user(VirtAddr::new(0xffff_ffff_ffff_fffc), b"too long"),
+        Err(UserCopyError::InvalidRange)
+    );
+}
+
+#[test_case]
+fn test_copy_checks_all_levels() {
+    use x86_64::structures::paging::{FrameAllocator, Mapper};
+
+    // A page that is user accessible itself, but whose page tables are
+    // not, so the CPU denies user accesses to it
+    let addr = VirtAddr::new(USER_START + 0x0080_0000_0000);
+    let page: Page = Page::containing_address(addr);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            let frame = frame_allocator.allocate_frame().expect("no frames left");
+            unsafe {
+                mapper
+                    .map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)
+                    .expect("map_to_with_table_flags failed")
+                    .flush();
+            }
+        })
+    });
+
+    let mut buf = [0; 4];
+    assert_eq!(copy_from_user(&mut buf, addr), Err(UserCopyError::AccessDenied(addr)));
+    assert_eq!(copy_to_user(addr, b"evil"), Err(UserCopyError::AccessDenied(addr)));
+
+    super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            let (frame, flush) = mapper.unmap(page).expect("unmap failed");
+            flush.flush();
+            unsafe { frame_allocator.deallocate(frame, 0) };
+        })
+    });
+}
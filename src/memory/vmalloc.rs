@@ -0,0 +1,249 @@
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+};
+
+/// Start of the virtual address range for `vmap`. Like every kernel level 4
+/// entry, its entry exists before any address space is created, so the
+/// mappings are visible in all address spaces.
+pub const VMALLOC_START: u64 = 0x_5560_0000_0000;
+/// Size of the `vmap` range (64 GiB).
+pub const VMALLOC_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// Maximum number of areas that can be allocated at once.
+const MAX_AREAS: usize = 64;
+
+/// Unmapped pages below every area, so an access running over the end of
+/// an area faults instead of silently hitting the next one.
+const GUARD_PAGES: u64 = 1;
+
+/// All allocated areas.
+static AREAS: Mutex<[Option<VmArea>; MAX_AREAS]> = Mutex::new([None; MAX_AREAS]);
+
+/// A range of kernel virtual address space handed out by the allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct VmArea {
+    /// First mapped page, the guard pages lie below it
+    pub start: Page,
+    /// Number of mapped pages
+    pub pages: u64,
+    /// The frames were allocated by `vmap` and are freed by `vunmap`
+    pub owns_frames: bool,
+}
+
+impl VmArea {
+    /// First page of the reserved range, including the guard pages.
+    fn reserved_start(&self) -> Page {
+        self.start - GUARD_PAGES
+    }
+
+    /// Page right after the area.
+    fn end(&self) -> Page {
+        self.start + self.pages
+    }
+}
+
+/// Errors that can occur when mapping or unmapping an area.
+#[derive(Debug)]
+pub enum VmapError {
+    /// A size of 0 was requested
+    ZeroSize,
+    /// No free range of the requested size is left, or all area slots are
+    /// in use
+    OutOfVirtualSpace,
+    /// No area starts at the passed address
+    NotAllocated,
+    /// Mapping a page of the area failed
+    Map(MapToError<Size4KiB>),
+}
+
+/// Reserves a range of `pages` pages (plus the guard pages below it).
+pub(super) fn reserve(pages: u64, owns_frames: bool) -> Result<VmArea, VmapError> {
+    if pages == 0 {
+        return Err(VmapError::ZeroSize);
+    }
+    // Larger requests never fit, rejecting them first also keeps the
+    // page arithmetic below from overflowing
+    if pages > VMALLOC_SIZE / 4096 - GUARD_PAGES {
+        return Err(VmapError::OutOfVirtualSpace);
+    }
+    let region_start = Page::containing_address(VirtAddr::new(VMALLOC_START));
+    let region_end = Page::containing_address(VirtAddr::new(VMALLOC_START + VMALLOC_SIZE));
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+
+        // First fit: try the start of the region and the end of every
+        // area, the lowest candidate that doesn't overlap anything wins
+        let candidates = core::iter::once(region_start)
+            .chain(areas.iter().flatten().map(|area| area.end()));
+        let start = candidates
+            .filter(|&candidate| {
+                let end = candidate + GUARD_PAGES + pages;
+                end <= region_end
+                    && areas
+                        .iter()
+                        .flatten()
+                        .all(|area| end <= area.reserved_start() || candidate >= area.end())
+            })
+            .min()
+            .ok_or(VmapError::OutOfVirtualSpace)?;
+
+        let area = VmArea {
+            start: start + GUARD_PAGES,
+            pages,
+            owns_frames,
+        };
+        let slot = areas
+            .iter_mut()
+            .find(|a| a.is_none())
+            .ok_or(VmapError::OutOfVirtualSpace)?;
+        *slot = Some(area);
+        Ok(area)
+    })
+}
+
+/// Removes the area starting at `start` from the allocator, its pages must
+/// have been unmapped already.
+pub(super) fn release(start: VirtAddr) -> Option<VmArea> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        AREAS
+            .lock()
+            .iter_mut()
+            .find(|a| matches!(a, Some(a) if a.start.start_address() == start))
+            .and_then(|a| a.take())
+    })
+}
+
+/// Maps `size` bytes (rounded up to whole pages) of freshly allocated,
+/// not necessarily contiguous frames into a contiguous range of kernel
+/// virtual address space and returns its start address.
+pub fn vmap(size: usize, flags: PageTableFlags) -> Result<VirtAddr, VmapError> {
+    let pages = (size as u64).div_ceil(4096);
+    let area = reserve(pages, true)?;
+    let flags = flags | PageTableFlags::PRESENT;
+
+    let result = super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            for (i, page) in Page::range(area.start, area.end()).enumerate() {
+                let mapped = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)
+                    .and_then(|frame| unsafe {
+                        mapper
+                            .map_to(page, frame, flags, frame_allocator)
+                            .inspect_err(|_| frame_allocator.deallocate(frame, 0))
+                    });
+                match mapped {
+                    Ok(flush) => flush.flush(),
+                    Err(err) => {
+                        // Undo the pages mapped so far
+                        unmap_area(area.start, i as u64, true, mapper, frame_allocator);
+                        return Err(VmapError::Map(err));
+                    }
+                }
+            }
+            Ok(())
+        })
+    });
+
+    if let Err(err) = result {
+        release(area.start.start_address());
+        return Err(err);
+    }
+    Ok(area.start.start_address())
+}
+
+/// Unmaps the area starting at `addr` that was returned by `vmap` and frees
+/// its frames.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the area
+/// is no longer accessed.
+pub unsafe fn vunmap(addr: VirtAddr) -> Result<(), VmapError> {
+    let area = release(addr).ok_or(VmapError::NotAllocated)?;
+    super::with_mapper(|mapper| {
+        super::with_frame_allocator(|frame_allocator| {
+            unmap_area(area.start, area.pages, area.owns_frames, mapper, frame_allocator);
+        })
+    });
+    Ok(())
+}
+
+/// Unmaps `pages` pages starting at `start`, their frames are freed if
+/// `owns_frames` is set.
+pub(super) fn unmap_area(
+    start: Page,
+    pages: u64,
+    owns_frames: bool,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut super::buddy::BuddyFrameAllocator,
+) {
+    for page in Page::range(start, start + pages) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            if owns_frames {
+                unsafe { frame_allocator.deallocate(frame, 0) };
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_vmap_vunmap() {
+    use x86_64::structures::paging::Translate;
+
+    let used_frames = super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames());
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let first = vmap(3 * 4096, flags).expect("vmap failed");
+    let second = vmap(100, flags).expect("vmap failed");
+    assert_eq!(
+        super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames()),
+        used_frames + 4
+    );
+
+    // The areas are separated by an unmapped guard page
+    assert!(second >= first + 4 * 4096u64);
+    assert_eq!(super::with_mapper(|mapper| mapper.translate_addr(second - 1u64)), None);
+
+    // The whole first area is usable
+    let ptr: *mut u64 = first.as_mut_ptr();
+    let len = 3 * 4096 / 8;
+    unsafe {
+        for i in 0..len {
+            ptr.add(i).write_volatile(i as u64);
+        }
+        assert_eq!(ptr.add(len - 1).read_volatile(), len as u64 - 1);
+    }
+
+    unsafe {
+        vunmap(first).expect("vunmap failed");
+        vunmap(second).expect("vunmap failed");
+    }
+    assert_eq!(super::with_mapper(|mapper| mapper.translate_addr(first)), None);
+    assert_eq!(
+        super::with_frame_allocator(|frame_allocator| frame_allocator.used_frames()),
+        used_frames
+    );
+    assert!(matches!(unsafe { vunmap(first) }, Err(VmapError::NotAllocated)));
+}
+
+#[test_case]
+fn test_vmap_reuses_freed_ranges() {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    assert!(matches!(vmap(0, flags), Err(VmapError::ZeroSize)));
+    assert!(matches!(vmap(usize::MAX, flags), Err(VmapError::OutOfVirtualSpace)));
+
+    let first = vmap(4096, flags).expect("vmap failed");
+    let second = vmap(4096, flags).expect("vmap failed");
+    unsafe { vunmap(first).expect("vunmap failed") };
+    // First fit hands out the lowest free range again
+    let third = vmap(4096, flags).expect("vmap failed");
+    assert_eq!(third, first);
+    unsafe {
+        vunmap(second).expect("vunmap failed");
+        vunmap(third).expect("vunmap failed");
+    }
+}
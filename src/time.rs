@@ -0,0 +1,360 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+use crate::{cpu, println};
+
+pub mod hpet;
+
+/// Frequency of the oscillator driving the PIT in Hz.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// Timer interrupt rate used if the boot configuration doesn't choose one.
+pub const DEFAULT_TIMER_FREQUENCY: u32 = 1000;
+
+/// Data port of PIT channel 0, which is connected to IRQ 0.
+const PIT_CHANNEL_0: u16 = 0x40;
+/// Mode/command port of the PIT.
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, low byte then high byte of the reload value, mode 2 (rate
+/// generator), binary counting.
+const PIT_RATE_GENERATOR: u8 = 0b0011_0100;
+
+/// Data port of PIT channel 2, whose gate is controlled through port 0x61.
+const PIT_CHANNEL_2: u16 = 0x42;
+/// Channel 2, low byte then high byte, mode 0 (interrupt on terminal
+/// count), binary counting.
+const PIT_ONE_SHOT_2: u8 = 0b1011_0000;
+/// The PC speaker port: bit 0 gates channel 2, bit 1 connects it to the
+/// speaker and bit 5 reads the output of channel 2.
+const SPEAKER_PORT: u16 = 0x61;
+const SPEAKER_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const SPEAKER_PIT_OUTPUT: u8 = 1 << 5;
+
+/// Length of the TSC calibration in PIT cycles (~10ms).
+const CALIBRATION_CYCLES: u16 = 11932;
+
+/// Number of TSC cycles after which the calibration against the PIT or
+/// the HPET gives up, ~1s even at 10 GHz.
+const CALIBRATION_TIMEOUT: u64 = 10_000_000_000;
+
+/// Femtoseconds per second, the unit the tick length is kept in.
+const FS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// Timer interrupts received since the timer was started.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Length of a tick in femtoseconds, initially the power-on rate of the
+/// PIT (a divisor of 65536).
+static TICK_LENGTH_FS: AtomicU64 = AtomicU64::new(pit_tick_length_fs(1 << 16));
+
+/// Uptime in nanoseconds when the tick length was last changed.
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+/// Tick count when the tick length was last changed.
+static BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the HPET raises the timer interrupt instead of the PIT.
+static HPET_TIMER: AtomicBool = AtomicBool::new(false);
+
+/// The devices that can raise the timer interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerSource {
+    /// Channel 0 of the 8253/8254 programmable interval timer
+    Pit,
+    /// Timer 0 of the high precision event timer, in legacy replacement
+    /// mode
+    Hpet,
+}
+
+/// Starts the timer interrupt at `frequency` Hz on the `preferred` timer
+/// and returns the timer that is used. Falls back to the PIT if the HPET
+/// is not usable.
+///
+/// The HPET registers are mapped with `memory::mmio`, so using it requires
+/// the page table and frame allocator to be handed over to `memory` first.
+pub fn init(preferred: TimerSource, frequency: u32) -> TimerSource {
+    if preferred == TimerSource::Hpet {
+        let periodic = hpet::TimerMode::Periodic(frequency);
+        match hpet::init().and_then(|()| hpet::start_timer(periodic)) {
+            Ok(_) => return TimerSource::Hpet,
+            Err(err) => println!("HPET not usable ({:?}), using the PIT", err),
+        }
+    }
+    init_pit(frequency);
+    TimerSource::Pit
+}
+
+/// Returns the device that raises the timer interrupt.
+pub fn timer_source() -> TimerSource {
+    if HPET_TIMER.load(Ordering::SeqCst) {
+        TimerSource::Hpet
+    } else {
+        TimerSource::Pit
+    }
+}
+
+/// Length of a tick of the PIT with the given reload value.
+const fn pit_tick_length_fs(divisor: u32) -> u64 {
+    (divisor as u128 * FS_PER_SECOND as u128 / PIT_FREQUENCY as u128) as u64
+}
+
+/// Returns the reload value that comes closest to `frequency` interrupts
+/// per second, the PIT can't go below ~18.2 Hz.
+fn pit_divisor(frequency: u32) -> u16 {
+    assert!(frequency > 0, "timer frequency must not be 0");
+    let divisor = (PIT_FREQUENCY + frequency / 2) / frequency;
+    divisor.clamp(1, u16::MAX as u32) as u16
+}
+
+/// Programs PIT channel 0 to raise the timer interrupt `frequency` times
+/// per second and returns the frequency that is actually used, which
+/// differs slightly because the PIT divides a fixed oscillator.
+pub fn init_pit(frequency: u32) -> u32 {
+    let divisor = pit_divisor(frequency);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut command = Port::<u8>::new(PIT_COMMAND);
+        let mut data = Port::<u8>::new(PIT_CHANNEL_0);
+        unsafe {
+            command.write(PIT_RATE_GENERATOR);
+            data.write(divisor as u8);
+            data.write((divisor >> 8) as u8);
+        }
+        set_tick_length(pit_tick_length_fs(divisor as u32));
+    });
+    timer_frequency()
+}
+
+/// Switches the clock to ticks of `length_fs` femtoseconds, called
+/// whenever the timer is reprogrammed. The time up to now is kept as the
+/// base of `uptime`, so it doesn't jump when the rate changes.
+pub(crate) fn set_tick_length(length_fs: u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let now = ticks();
+        BASE_NS.store(uptime_at(now).as_nanos() as u64, Ordering::SeqCst);
+        BASE_TICKS.store(now, Ordering::SeqCst);
+        TICK_LENGTH_FS.store(length_fs, Ordering::SeqCst);
+    });
+}
+
+/// Returns the rate of the timer interrupt in Hz, rounded down.
+pub fn timer_frequency() -> u32 {
+    (FS_PER_SECOND / TICK_LENGTH_FS.load(Ordering::SeqCst)) as u32
+}
+
+/// Advances the clock by one tick, called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts since the timer was started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since the timer was started, with the resolution of
+/// one tick.
+pub fn uptime() -> Duration {
+    uptime_at(ticks())
+}
+
+/// Returns the uptime at tick `ticks`, which must not be before the last
+/// change of the tick length.
+fn uptime_at(ticks: u64) -> Duration {
+    // Computed from the ticks since the last rate change each time, so
+    // rounding errors don't add up over time
+    let ticks = ticks - BASE_TICKS.load(Ordering::SeqCst);
+    let fs = ticks as u128 * TICK_LENGTH_FS.load(Ordering::SeqCst) as u128;
+    Duration::from_nanos(BASE_NS.load(Ordering::SeqCst) + (fs / 1_000_000) as u64)
+}
+
+/// TSC cycles per second, 0 until `calibrate_tsc` succeeded.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value at the end of the calibration, `now_ns` counts from there.
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+/// The CPU reported an invariant TSC.
+static TSC_INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// Reads the time stamp counter.
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Counts the TSC cycles during `CALIBRATION_CYCLES` cycles of PIT channel
+/// 2 and returns them with the elapsed femtoseconds. Channel 2 is polled,
+/// so neither interrupts nor the rate of channel 0 matter.
+///
+/// Returns `None` if the output of channel 2 doesn't go high within
+/// `CALIBRATION_TIMEOUT` TSC cycles, e.g. because the PIT is not emulated.
+fn measure_tsc_with_pit() -> Option<(u64, u64)> {
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut data = Port::<u8>::new(PIT_CHANNEL_2);
+    unsafe {
+        // Open the gate of channel 2 but keep the speaker quiet
+        let saved = speaker.read();
+        speaker.write((saved & !SPEAKER_ENABLE) | SPEAKER_GATE);
+
+        // Counting starts once the count is loaded, the output goes high
+        // when it reaches 0
+        command.write(PIT_ONE_SHOT_2);
+        data.write(CALIBRATION_CYCLES as u8);
+        data.write((CALIBRATION_CYCLES >> 8) as u8);
+        let start = rdtsc();
+        let result = loop {
+            let done = speaker.read() & SPEAKER_PIT_OUTPUT != 0;
+            let cycles = rdtsc() - start;
+            if done {
+                break Some((cycles, pit_tick_length_fs(CALIBRATION_CYCLES as u32)));
+            }
+            if cycles >= CALIBRATION_TIMEOUT {
+                break None;
+            }
+            core::hint::spin_loop();
+        };
+
+        // Leave the gate and the speaker as they were
+        speaker.write(saved);
+        result
+    }
+}
+
+/// Counts the TSC cycles during ~10ms of the HPET main counter and returns
+/// them with the elapsed femtoseconds.
+///
+/// Returns `None` if the HPET is not enabled or its counter doesn't
+/// advance by ~10ms within `CALIBRATION_TIMEOUT` TSC cycles.
+fn measure_tsc_with_hpet(period_fs: u64) -> Option<(u64, u64)> {
+    let hpet_cycles = 10_000_000_000_000 / period_fs;
+    let start_counter = hpet::counter()?;
+    let start = rdtsc();
+    loop {
+        let elapsed = hpet::counter()?.wrapping_sub(start_counter);
+        let cycles = rdtsc() - start;
+        if elapsed >= hpet_cycles {
+            return Some((cycles, elapsed * period_fs));
+        }
+        if cycles >= CALIBRATION_TIMEOUT {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Measures the TSC frequency against the HPET, or the PIT if the HPET is
+/// not enabled or its counter doesn't run, and starts the nanosecond clock of `now_ns`. Returns the
+/// frequency in Hz, or `None` if the PIT didn't count and `now_ns` keeps
+/// the resolution of the timer ticks.
+///
+/// Without an invariant TSC (see `cpu::has_invariant_tsc`) the rate can
+/// change with the power state of the CPU, the timestamps are then only as
+/// accurate as the calibration.
+pub fn calibrate_tsc() -> Option<u64> {
+    TSC_INVARIANT.store(cpu::has_invariant_tsc(), Ordering::SeqCst);
+
+    let (cycles, fs) = x86_64::instructions::interrupts::without_interrupts(|| {
+        hpet::period_fs()
+            .and_then(measure_tsc_with_hpet)
+            .or_else(measure_tsc_with_pit)
+    })?;
+    let frequency = (cycles as u128 * FS_PER_SECOND as u128 / fs as u128) as u64;
+    TSC_BASE.store(rdtsc(), Ordering::SeqCst);
+    TSC_FREQUENCY.store(frequency, Ordering::SeqCst);
+    Some(frequency)
+}
+
+/// Returns the calibrated TSC frequency in Hz, `None` before
+/// `calibrate_tsc` was called.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::SeqCst) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Returns `true` if the calibrated TSC runs at a constant rate.
+pub fn tsc_is_invariant() -> bool {
+    TSC_INVARIANT.load(Ordering::SeqCst)
+}
+
+/// Returns a monotonic timestamp in nanoseconds, counted from the TSC
+/// calibration. Falls back to the resolution of the timer ticks if the TSC
+/// was not calibrated.
+pub fn now_ns() -> u64 {
+    let frequency = TSC_FREQUENCY.load(Ordering::SeqCst);
+    if frequency == 0 {
+        return uptime().as_nanos() as u64;
+    }
+    let cycles = rdtsc().saturating_sub(TSC_BASE.load(Ordering::SeqCst));
+    (cycles as u128 * 1_000_000_000 / frequency as u128) as u64
+}
+
+#[test_case]
+fn test_pit_divisor() {
+    assert_eq!(pit_divisor(1000), 1193);
+    assert_eq!(pit_divisor(100), 11932);
+    // Out of range rates are clamped
+    assert_eq!(pit_divisor(1), u16::MAX);
+    assert_eq!(pit_divisor(PIT_FREQUENCY * 2), 1);
+}
+
+#[test_case]
+fn test_uptime_advances() {
+    assert_eq!(timer_frequency(), PIT_FREQUENCY / 1193);
+
+    let start_ticks = ticks();
+    let start = uptime();
+    while ticks() < start_ticks + 10 {
+        x86_64::instructions::hlt();
+    }
+    // 10 ticks at ~1000 Hz are ~10ms
+    let elapsed = uptime() - start;
+    assert!(elapsed >= Duration::from_millis(9), "elapsed only {:?}", elapsed);
+}
+
+#[test_case]
+fn test_uptime_across_rate_change() {
+    let wait_ticks = |count| {
+        let start_ticks = ticks();
+        while ticks() < start_ticks + count {
+            x86_64::instructions::hlt();
+        }
+    };
+
+    // Neither the longer nor the shorter ticks are applied to the time
+    // before the change
+    let before = uptime();
+    init_pit(100);
+    assert!(uptime() >= before);
+    wait_ticks(2);
+    let slow = uptime();
+    assert!(slow >= before + Duration::from_millis(10), "{:?} -> {:?}", before, slow);
+
+    init_pit(DEFAULT_TIMER_FREQUENCY);
+    assert!(uptime() >= slow);
+    wait_ticks(2);
+    assert!(uptime() > slow);
+    assert_eq!(timer_frequency(), PIT_FREQUENCY / 1193);
+}
+
+#[test_case]
+fn test_now_ns_resolution() {
+    let frequency = tsc_frequency().expect("TSC not calibrated");
+    // Even slow emulation runs the TSC at more than 10 MHz
+    assert!(frequency > 10_000_000, "TSC frequency {} Hz", frequency);
+
+    // Consecutive timestamps are monotonic and much finer than a tick
+    let first = now_ns();
+    let second = now_ns();
+    assert!(second >= first);
+    assert!(second - first < 1_000_000);
+
+    // The clock doesn't run slower than the timer ticks
+    let start_ns = now_ns();
+    let start_ticks = ticks();
+    while ticks() < start_ticks + 20 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = Duration::from_nanos(now_ns() - start_ns);
+    assert!(elapsed >= Duration::from_millis(15), "elapsed only {:?}", elapsed);
+}
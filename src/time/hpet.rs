@@ -0,0 +1,241 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::acpi;
+use crate::memory::mmio::{self, CacheMode, Mmio};
+use crate::memory::vmalloc::VmapError;
+
+/// Register indices in `u64`s, i.e. the byte offsets 0x000, 0x010 and
+/// 0x0f0 divided by 8
+const GENERAL_CAPABILITIES: usize = 0x00;
+const GENERAL_CONFIG: usize = 0x02;
+const MAIN_COUNTER: usize = 0x1e;
+/// Size of the register block
+const REGISTERS_SIZE: usize = 0x400;
+
+/// Capability bits: the main counter is 64 bits wide, and timers 0 and 1
+/// can replace the PIT and RTC interrupts.
+const CAP_COUNTER_64: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+
+/// Configuration bits: the main counter runs, and timer 0 raises IRQ 0 and
+/// timer 1 IRQ 8 instead of the PIT and RTC.
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+/// Timer configuration bits
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+/// Lets the next comparator write set the period instead of the
+/// comparator value in periodic mode
+const TIMER_VALUE_SET: u64 = 1 << 6;
+
+/// Longest main counter period the specification allows (100ns).
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// The HPET, set by `init`.
+static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+
+/// Virtual address of the main counter and its period in femtoseconds, so
+/// the clock can be read without taking the lock. 0 until `init`.
+static COUNTER_ADDR: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+/// Errors that can occur when setting up the HPET.
+#[derive(Debug)]
+pub enum HpetError {
+    /// The ACPI tables describe no memory mapped HPET
+    NotPresent,
+    /// The HPET lacks a feature the kernel needs, i.e. a 64 bit counter,
+    /// the legacy replacement route or periodic mode of timer 0
+    Unsupported,
+    /// `init` was not called or failed
+    NotInitialized,
+    /// Mapping the registers failed
+    Map(VmapError),
+}
+
+/// How timer 0 raises the timer interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// The interrupt fires `frequency` times per second
+    Periodic(u32),
+    /// The interrupt fires once after the delay
+    OneShot(Duration),
+}
+
+/// The memory mapped registers of the HPET.
+struct Hpet {
+    regs: Mmio<u64>,
+    /// Length of a main counter cycle in femtoseconds
+    period_fs: u64,
+    /// Smallest periodic comparator increment, in cycles
+    min_tick: u64,
+}
+
+impl Hpet {
+    /// Index of the configuration register of a timer (byte offset
+    /// 0x100 + 0x20 * timer).
+    fn timer_config(timer: usize) -> usize {
+        0x20 + 4 * timer
+    }
+
+    /// Index of the comparator register of a timer (byte offset
+    /// 0x108 + 0x20 * timer).
+    fn timer_comparator(timer: usize) -> usize {
+        0x21 + 4 * timer
+    }
+}
+
+/// Maps the HPET registers and starts its main counter. Its timers stay
+/// disabled until `start_timer`. Does nothing if the HPET is already
+/// initialized. Needs the memory management to map the registers.
+pub fn init() -> Result<(), HpetError> {
+    if is_enabled() {
+        return Ok(());
+    }
+    let info = acpi::hpet().ok_or(HpetError::NotPresent)?;
+    let mut regs: Mmio<u64> =
+        unsafe { mmio::map_mmio(info.address, REGISTERS_SIZE, CacheMode::Uncacheable) }
+            .map_err(HpetError::Map)?;
+
+    let capabilities = regs[GENERAL_CAPABILITIES].read();
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS || capabilities & CAP_COUNTER_64 == 0 {
+        return Err(HpetError::Unsupported);
+    }
+
+    // The counter can only be written while it is halted
+    let config = regs[GENERAL_CONFIG].read() & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    regs[GENERAL_CONFIG].write(config);
+    regs[MAIN_COUNTER].write(0);
+    let timer_0 = regs[Hpet::timer_config(0)].read();
+    regs[Hpet::timer_config(0)].write(timer_0 & !TIMER_INTERRUPT_ENABLE);
+    regs[GENERAL_CONFIG].write(config | CONFIG_ENABLE);
+
+    let hpet = Hpet {
+        period_fs,
+        min_tick: info.min_tick as u64,
+        regs,
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let counter = hpet.regs.virt_addr().as_u64() + MAIN_COUNTER as u64 * 8;
+        PERIOD_FS.store(period_fs, Ordering::SeqCst);
+        COUNTER_ADDR.store(counter, Ordering::SeqCst);
+        *HPET.lock() = Some(hpet);
+    });
+    Ok(())
+}
+
+/// Returns `true` if the main counter runs.
+pub fn is_enabled() -> bool {
+    COUNTER_ADDR.load(Ordering::SeqCst) != 0
+}
+
+/// Returns the length of a main counter cycle in femtoseconds.
+pub fn period_fs() -> Option<u64> {
+    match PERIOD_FS.load(Ordering::SeqCst) {
+        0 => None,
+        period => Some(period),
+    }
+}
+
+/// Reads the main counter.
+pub fn counter() -> Option<u64> {
+    match COUNTER_ADDR.load(Ordering::SeqCst) {
+        0 => None,
+        addr => Some(unsafe { (addr as *const u64).read_volatile() }),
+    }
+}
+
+/// Returns the time since `init` in nanoseconds, with the resolution of
+/// the main counter.
+pub fn nanos() -> Option<u64> {
+    let cycles = counter()?;
+    Some((cycles as u128 * PERIOD_FS.load(Ordering::SeqCst) as u128 / 1_000_000) as u64)
+}
+
+/// Programs timer 0 to raise the timer interrupt in `mode` and routes it
+/// to IRQ 0 in place of the PIT. Returns the length of a period in
+/// femtoseconds, or the actual delay for a one-shot timer.
+///
+/// With the legacy replacement route the I/O APIC receives the interrupt
+/// on GSI 2, which is where the MADT overrides send ISA IRQ 0 to.
+///
+/// A periodic timer becomes the system timer, `time::uptime` and
+/// `time::timer_frequency` follow its rate from then on. A one-shot timer
+/// raises a single tick, so `time::uptime` stands still while it runs.
+pub fn start_timer(mode: TimerMode) -> Result<u64, HpetError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut hpet = HPET.lock();
+        let hpet = hpet.as_mut().ok_or(HpetError::NotInitialized)?;
+        let capabilities = hpet.regs[GENERAL_CAPABILITIES].read();
+        let timer_config = hpet.regs[Hpet::timer_config(0)].read();
+        if capabilities & CAP_LEGACY_ROUTE == 0 {
+            return Err(HpetError::Unsupported);
+        }
+
+        // The interrupt is disabled while the comparator is changed
+        let timer_config = timer_config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC);
+        hpet.regs[Hpet::timer_config(0)].write(timer_config);
+        let now = hpet.regs[MAIN_COUNTER].read();
+
+        let cycles = match mode {
+            TimerMode::Periodic(frequency) => {
+                assert!(frequency > 0, "timer frequency must not be 0");
+                if timer_config & TIMER_PERIODIC_CAP == 0 {
+                    return Err(HpetError::Unsupported);
+                }
+                let cycles = super::FS_PER_SECOND / frequency as u64 / hpet.period_fs;
+                let cycles = cycles.max(hpet.min_tick).max(1);
+                // The first write sets the comparator, the second one the
+                // period that is added on every expiry
+                hpet.regs[Hpet::timer_config(0)]
+                    .write(timer_config | TIMER_PERIODIC | TIMER_VALUE_SET);
+                hpet.regs[Hpet::timer_comparator(0)].write(now + cycles);
+                hpet.regs[Hpet::timer_comparator(0)].write(cycles);
+                hpet.regs[Hpet::timer_config(0)]
+                    .write(timer_config | TIMER_PERIODIC | TIMER_INTERRUPT_ENABLE);
+                cycles
+            }
+            TimerMode::OneShot(delay) => {
+                let cycles = (delay.as_nanos() * 1_000_000 / hpet.period_fs as u128) as u64;
+                let cycles = cycles.max(1);
+                hpet.regs[Hpet::timer_comparator(0)].write(now + cycles);
+                hpet.regs[Hpet::timer_config(0)].write(timer_config | TIMER_INTERRUPT_ENABLE);
+                cycles
+            }
+        };
+
+        let config = hpet.regs[GENERAL_CONFIG].read();
+        hpet.regs[GENERAL_CONFIG].write(config | CONFIG_LEGACY_ROUTE | CONFIG_ENABLE);
+        // The legacy route replaces the PIT, so the HPET raises the timer
+        // interrupt in either mode
+        let length_fs = cycles * hpet.period_fs;
+        if let TimerMode::Periodic(_) = mode {
+            super::set_tick_length(length_fs);
+        }
+        super::HPET_TIMER.store(true, Ordering::SeqCst);
+        Ok(length_fs)
+    })
+}
+
+#[test_case]
+fn test_hpet_counter() {
+    init().expect("HPET initialization failed");
+    let period = period_fs().expect("HPET not enabled");
+    assert!(period <= MAX_PERIOD_FS);
+
+    let first = counter().unwrap();
+    let start = nanos().unwrap();
+    // Wait for 1ms of main counter cycles
+    while nanos().unwrap() < start + 1_000_000 {
+        core::hint::spin_loop();
+    }
+    assert!(counter().unwrap() > first);
+
+    // A second init keeps the running counter
+    init().expect("HPET initialization failed");
+    assert!(nanos().unwrap() >= start + 1_000_000);
+}
@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use bootloader::{BootInfo, entry_point};
+use os::{serial_print, ExpectedCrash};
+
+/// The `div rcx` of `main` is reported as a divide error.
+static EXPECTED: ExpectedCrash = ExpectedCrash {
+    vector: 0,
+    name: "DIVIDE ERROR",
+    error_code: false,
+    instruction: Some(&[0x48, 0xf7, 0xf1]),
+    overflowed_stack: None,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // (not using a test harness)
+    serial_print!("divide_error::divide_by_zero...\t");
+
+    os::init_crash_test(boot_info, &EXPECTED);
+
+    // A division in Rust checks for 0 and panics instead
+    unsafe {
+        core::arch::asm!(
+            "div rcx",
+            inout("rax") 1u64 => _,
+            inout("rdx") 0u64 => _,
+            in("rcx") 0u64,
+        )
+    };
+
+    panic!("Execution continued after dividing by zero");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
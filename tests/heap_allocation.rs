@@ -0,0 +1,142 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use os::allocator::{HEAP_SIZE, heap_stats};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os::allocator;
+    use os::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    os::gdt::init_stacks(Default::default(), &mut mapper, &mut frame_allocator)
+        .expect("interrupt stack allocation failed");
+    // The page fault handler maps the heap pages through the kernel's
+    // page table
+    memory::init_mapper(mapper);
+    memory::init_frame_allocator(frame_allocator);
+    os::init(os::Config::default());
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info);
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn many_boxes() {
+    // Allocates far more than the heap size in total, so this only
+    // works if freed memory is reused
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[cfg(not(feature = "alloc-bump"))]
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+// A bump allocator can only reuse memory once every allocation was
+// freed, so a single long-lived allocation makes it run out of memory.
+#[cfg(feature = "alloc-bump")]
+#[test_case]
+fn many_boxes_long_lived() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    let long_lived = Box::new(1);
+    // Allocated through the allocator directly, the failure returns null
+    // instead of calling the allocation error handler
+    let layout = Layout::new::<usize>();
+    let exhausted = (0..HEAP_SIZE).any(|_| unsafe {
+        let ptr = alloc(layout);
+        if !ptr.is_null() {
+            dealloc(ptr, layout);
+        }
+        ptr.is_null()
+    });
+    assert!(exhausted, "the bump allocator reused memory of a live allocation");
+
+    // Without live allocations the whole heap is available again
+    drop(long_lived);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn long_and_short_lived_mix() {
+    // Keep some allocations alive while a lot of short-lived ones
+    // come and go in between (small enough to fit into the heap even
+    // when nothing is reused)
+    let mut long_lived = Vec::new();
+    for i in 0..20 {
+        long_lived.push(Box::new(i));
+        for j in 0..100 {
+            let x = Box::new([j; 4]);
+            assert_eq!(x[3], j);
+        }
+    }
+    for (i, x) in long_lived.iter().enumerate() {
+        assert_eq!(**x, i);
+    }
+}
+
+#[test_case]
+fn heap_stats_track_allocations() {
+    let before = heap_stats();
+    assert_eq!(before.size, HEAP_SIZE);
+
+    let values = Box::new([0u64; 16]);
+    let during = heap_stats();
+    assert_eq!(during.used, before.used + 128);
+    assert_eq!(during.free, before.free - 128);
+
+    drop(values);
+    assert_eq!(heap_stats(), before);
+}
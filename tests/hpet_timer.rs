@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use os::time::{self, hpet, TimerSource};
+use x86_64::instructions::interrupts::without_interrupts;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os::memory::{self, buddy::BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    os::gdt::init_stacks(Default::default(), &mut mapper, &mut frame_allocator)
+        .expect("interrupt stack allocation failed");
+    // The HPET and APIC registers are mapped through the kernel's page
+    // table
+    memory::init_mapper(mapper);
+    memory::init_frame_allocator(frame_allocator);
+    os::init(os::Config {
+        timer_source: TimerSource::Hpet,
+        ..Default::default()
+    });
+
+    test_main();
+
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info);
+}
+
+/// Spins until the HPET main counter advanced by `duration`, works
+/// without timer interrupts.
+fn spin_for(duration: Duration) {
+    let end = hpet::nanos().unwrap() + duration.as_nanos() as u64;
+    while hpet::nanos().unwrap() < end {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn hpet_raises_timer_interrupt() {
+    assert_eq!(time::timer_source(), TimerSource::Hpet);
+    assert_eq!(time::timer_frequency(), time::DEFAULT_TIMER_FREQUENCY);
+
+    let start_ticks = time::ticks();
+    let start = hpet::nanos().unwrap();
+    while time::ticks() < start_ticks + 20 {
+        x86_64::instructions::hlt();
+    }
+    // 20 ticks at 1000 Hz take at least ~20ms of main counter time
+    let elapsed = Duration::from_nanos(hpet::nanos().unwrap() - start);
+    assert!(elapsed >= Duration::from_millis(19), "elapsed only {:?}", elapsed);
+}
+
+#[test_case]
+fn tsc_calibrated_against_hpet() {
+    let frequency = time::tsc_frequency().expect("TSC not calibrated");
+    let start_ns = time::now_ns();
+    let start_hpet = hpet::nanos().unwrap();
+    spin_for(Duration::from_millis(10));
+    let tsc_elapsed = time::now_ns() - start_ns;
+    let hpet_elapsed = hpet::nanos().unwrap() - start_hpet;
+    // Both clocks agree to within 10%
+    assert!(tsc_elapsed.abs_diff(hpet_elapsed) < hpet_elapsed / 10, "TSC at {} Hz", frequency);
+}
+
+#[test_case]
+fn one_shot_timer_fires_once() {
+    let before = time::uptime();
+    let delay = hpet::start_timer(hpet::TimerMode::OneShot(Duration::from_millis(5)))
+        .expect("starting the one-shot timer failed");
+    assert_eq!(Duration::from_nanos(delay / 1_000_000), Duration::from_millis(5));
+
+    let start_ticks = time::ticks();
+    spin_for(Duration::from_millis(30));
+    assert_eq!(time::ticks(), start_ticks + 1);
+    let one_shot = time::uptime();
+    assert!(one_shot >= before);
+
+    // Back to a periodic system timer, the clock follows the new rate
+    hpet::start_timer(hpet::TimerMode::Periodic(500))
+        .expect("restarting the periodic timer failed");
+    assert_eq!(time::timer_source(), TimerSource::Hpet);
+    assert_eq!(time::timer_frequency(), 500);
+    // The time before the change keeps the old tick length
+    assert!(time::uptime() >= one_shot);
+    // Reads the tick count and uptime without a tick in between
+    let clock = || without_interrupts(|| (time::ticks(), time::uptime()));
+    let (start_ticks, start) = clock();
+    spin_for(Duration::from_millis(10));
+    let (end_ticks, end) = clock();
+    assert!(end_ticks > start_ticks);
+    // Each tick now counts 2ms
+    assert_eq!(end - start, Duration::from_millis(2) * (end_ticks - start_ticks) as u32);
+
+    hpet::start_timer(hpet::TimerMode::Periodic(time::DEFAULT_TIMER_FREQUENCY))
+        .expect("restarting the periodic timer failed");
+    assert_eq!(time::timer_frequency(), time::DEFAULT_TIMER_FREQUENCY);
+    assert!(time::uptime() >= end);
+}
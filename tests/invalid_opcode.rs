@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use bootloader::{BootInfo, entry_point};
+use os::{serial_print, ExpectedCrash};
+
+/// The `ud2` of `main` is reported as an invalid opcode.
+static EXPECTED: ExpectedCrash = ExpectedCrash {
+    vector: 6,
+    name: "INVALID OPCODE",
+    error_code: false,
+    instruction: Some(&[0x0f, 0x0b]),
+    overflowed_stack: None,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // (not using a test harness)
+    serial_print!("invalid_opcode::ud2...\t");
+
+    os::init_crash_test(boot_info, &EXPECTED);
+
+    unsafe { core::arch::asm!("ud2") };
+
+    panic!("Execution continued after an invalid opcode");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use bootloader::{BootInfo, entry_point};
+use os::{serial_print, ExpectedCrash};
+use os::memory::stack;
+
+/// The overflow of the test stack is caught by the page fault handler,
+/// which runs on its own stack and names the overflowed one.
+static EXPECTED: ExpectedCrash = ExpectedCrash {
+    vector: 14,
+    name: "PAGE FAULT",
+    error_code: true,
+    instruction: None,
+    overflowed_stack: Some("test stack"),
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // (not using a test harness)
+    serial_print!("kernel_stack_overflow::page_fault_report...\t");
+
+    let (mut mapper, mut frame_allocator) = os::init_crash_test(boot_info, &EXPECTED);
+
+    let stack = stack::allocate_stack("test stack", 4, &mut mapper, &mut frame_allocator)
+        .expect("stack allocation failed");
+
+    unsafe { stack::switch_to(&stack, overflow) }
+}
+
+extern "C" fn overflow() -> ! {
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
@@ -4,9 +4,13 @@
 
 use core::panic::PanicInfo;
 
+use bootloader::{BootInfo, entry_point};
 use os::{serial_print, serial_println, exit_qemu, QemuExitCode};
+use os::memory::{self, BootInfoFrameAllocator, stack};
 
 use lazy_static::lazy_static;
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 lazy_static! {
@@ -26,8 +30,18 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+    // The overflow must have hit the guard page of the test stack
+    match stack::overflowed_stack(Cr2::read()) {
+        Some(stack) if stack.name() == "test stack" => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        _ => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: double fault was not caused by the guard page\n");
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
     loop {}
 }
 
@@ -35,14 +49,30 @@ pub fn init_test_idt() {
     TEST_IDT.load();
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
     // (not using a test harness)
     serial_print!("stack_overflow::stack_overflow...\t");
 
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    os::gdt::init_stacks(Default::default(), &mut mapper, &mut frame_allocator)
+        .expect("interrupt stack allocation failed");
+
     os::gdt::init();
     init_test_idt();
 
+    let stack = stack::allocate_stack("test stack", 4, &mut mapper, &mut frame_allocator)
+        .expect("stack allocation failed");
+
+    unsafe { stack::switch_to(&stack, overflow) }
+}
+
+extern "C" fn overflow() -> ! {
     stack_overflow();
 
     panic!("Execution continued after stack overflow");
@@ -57,4 +87,4 @@ fn stack_overflow() {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     os::test_panic_handler(info)
-}
\ No newline at end of file
+}
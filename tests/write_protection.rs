@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+
+use bootloader::{BootInfo, entry_point};
+use os::{serial_print, serial_println, exit_qemu, QemuExitCode};
+use os::memory;
+
+use lazy_static::lazy_static;
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code == expected && Cr2::read() == code_address() {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault at {:?} ({:?})\n", Cr2::read(), error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    os::hlt_loop();
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+/// Address of some kernel code
+fn code_address() -> VirtAddr {
+    VirtAddr::new(main as *const () as u64)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // (not using a test harness)
+    serial_print!("write_protection::write_to_code...\t");
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::protect::protect_kernel(&mut mapper).expect("protecting the kernel failed");
+    init_test_idt();
+
+    // Overwrite the first byte of `main`
+    let code: *mut u8 = code_address().as_mut_ptr();
+    unsafe { code.write_volatile(0xcc) };
+
+    panic!("Execution continued after writing to code");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}